@@ -23,6 +23,11 @@ fn main() {
     // println!("cargo:rerun-if-changed=vial.json");
     // generate_vial_config();
 
+    // Host builds such as the simulator use the default linker setup
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("none") {
+        return;
+    }
+
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
@@ -0,0 +1,203 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use defmt::Format;
+
+use crate::{
+    board::{Hand, Pin},
+    text::TextBuffer,
+};
+
+/// Full scale of a 12-bit SAADC sample with gain 1/6 and the internal 0.6 V
+/// reference, in millivolts
+const FULL_SCALE_MV: u64 = 3600;
+const RESOLUTION: u64 = 1 << 12;
+
+/// Open-circuit voltage of a single LiPo cell against its remaining charge,
+/// from full to empty
+const LIPO_CURVE: [(u16, u8); 12] = [
+    (4200, 100),
+    (4150, 95),
+    (4110, 90),
+    (4080, 85),
+    (4020, 80),
+    (3980, 70),
+    (3950, 60),
+    (3910, 50),
+    (3870, 40),
+    (3840, 30),
+    (3800, 15),
+    (3500, 0),
+];
+
+/// SAADC input the battery is measured on
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum BatteryInput {
+    /// The internal VDDH/5 divider, for boards running the nRF52840 in
+    /// high-voltage mode straight from the battery
+    VddhDiv5,
+    /// Analog input AIN0 to AIN7 behind an external divider. The input's
+    /// pin must not be used by the matrix, see
+    /// [`BoardConfig::validated`](crate::board::BoardConfig::validated).
+    Ain(u8),
+}
+
+impl BatteryInput {
+    /// GPIO the input is on, `None` for internal inputs and unknown AINs
+    pub const fn pin(&self) -> Option<Pin> {
+        let pin = match self {
+            BatteryInput::VddhDiv5 => return None,
+            BatteryInput::Ain(0) => 2,
+            BatteryInput::Ain(1) => 3,
+            BatteryInput::Ain(2) => 4,
+            BatteryInput::Ain(3) => 5,
+            BatteryInput::Ain(4) => 28,
+            BatteryInput::Ain(5) => 29,
+            BatteryInput::Ain(6) => 30,
+            BatteryInput::Ain(7) => 31,
+            BatteryInput::Ain(_) => return None,
+        };
+        Some(Pin::p0(pin))
+    }
+}
+
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub struct BatteryConfig {
+    pub input: BatteryInput,
+    /// Battery voltage over the voltage seen at the input as
+    /// `(numerator, denominator)`, e.g. `(5, 1)` for VDDH/5 or `(3, 2)` for an
+    /// external divider of 1 MΩ over 2 MΩ
+    pub divider: (u16, u16),
+}
+
+impl BatteryConfig {
+    /// Converts a raw SAADC sample to the battery voltage in millivolts
+    pub const fn millivolts(&self, sample: i16) -> u16 {
+        let sample = if sample < 0 { 0 } else { sample as u64 };
+        let (numerator, denominator) = self.divider;
+        let mv = sample * FULL_SCALE_MV * numerator as u64 / (RESOLUTION * denominator as u64);
+        if mv > u16::MAX as u64 {
+            u16::MAX
+        } else {
+            mv as u16
+        }
+    }
+}
+
+/// Estimates the remaining charge of a LiPo cell from its voltage by linear
+/// interpolation along the discharge curve
+pub fn percentage(millivolts: u16) -> u8 {
+    let (full_mv, _) = LIPO_CURVE[0];
+    if millivolts >= full_mv {
+        return 100;
+    }
+
+    for pair in LIPO_CURVE.windows(2) {
+        let (upper_mv, upper_pct) = pair[0];
+        let (lower_mv, lower_pct) = pair[1];
+        if millivolts >= lower_mv {
+            let span_mv = (upper_mv - lower_mv) as u32;
+            let span_pct = (upper_pct - lower_pct) as u32;
+            let above = (millivolts - lower_mv) as u32;
+            return lower_pct + (above * span_pct / span_mv) as u8;
+        }
+    }
+
+    0
+}
+
+/// Exponential moving average over battery readings, so a single sample
+/// taken during a radio burst does not make the level jump around
+#[derive(Copy, Debug, Clone, Default, Eq, PartialEq, Format)]
+pub struct BatteryFilter {
+    /// Average in units of `1 / 2^SHIFT` mV, so changes smaller than
+    /// `2^SHIFT` mV add up instead of being rounded away
+    scaled: Option<u32>,
+}
+
+impl BatteryFilter {
+    /// Each new reading moves the average by `1 / 2^SHIFT` of the difference
+    const SHIFT: u32 = 2;
+
+    pub const fn new() -> Self {
+        Self { scaled: None }
+    }
+
+    /// Folds a new reading into the average and returns the smoothed value
+    pub fn update(&mut self, millivolts: u16) -> u16 {
+        let millivolts = u32::from(millivolts);
+        let scaled = match self.scaled {
+            None => millivolts << Self::SHIFT,
+            Some(scaled) => scaled - (scaled >> Self::SHIFT) + millivolts,
+        };
+        self.scaled = Some(scaled);
+        (scaled >> Self::SHIFT) as u16
+    }
+}
+
+/// Latest smoothed battery reading, shared between the monitor task and the
+/// interfaces reporting it
+pub struct BatteryState {
+    millivolts: AtomicU16,
+}
+
+impl BatteryState {
+    const UNKNOWN: u16 = 0;
+
+    pub const fn new() -> Self {
+        Self {
+            millivolts: AtomicU16::new(Self::UNKNOWN),
+        }
+    }
+
+    pub fn set(&self, millivolts: u16) {
+        self.millivolts.store(millivolts, Ordering::Relaxed);
+    }
+
+    /// Battery voltage in millivolts, or `None` before the first reading
+    pub fn millivolts(&self) -> Option<u16> {
+        match self.millivolts.load(Ordering::Relaxed) {
+            Self::UNKNOWN => None,
+            millivolts => Some(millivolts),
+        }
+    }
+
+    /// Remaining charge in percent, or `None` before the first reading
+    pub fn percentage(&self) -> Option<u8> {
+        self.millivolts().map(percentage)
+    }
+}
+
+impl Default for BatteryState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Longest text produced by [`LevelText`], `L 100%`
+const LEVEL_TEXT_LEN: usize = 6;
+
+/// Battery level of a half as typed by the battery level key, e.g. `L 87%`,
+/// with `?` before the first reading
+pub struct LevelText(TextBuffer<LEVEL_TEXT_LEN>);
+
+impl LevelText {
+    pub fn new(hand: Hand, level: Option<u8>) -> Self {
+        let mut text = TextBuffer::new();
+        text.push_str(match hand {
+            Hand::Left => "L ",
+            Hand::Right => "R ",
+        });
+        match level {
+            Some(level) => {
+                text.push_decimal(level.min(100) as u32);
+                text.push(b'%');
+            }
+            None => text.push(b'?'),
+        }
+        Self(text)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
@@ -0,0 +1,159 @@
+use defmt::Format;
+use embassy_time::Duration;
+
+use crate::{battery::BatteryConfig, matrix::MatrixConfig};
+
+pub mod nrfmicro;
+
+/// A GPIO pin identified by its port and pin number, e.g. `Pin::p0(31)` for
+/// P0.31.
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub struct Pin {
+    pub port: u8,
+    pub pin: u8,
+}
+
+impl Pin {
+    pub const fn p0(pin: u8) -> Self {
+        Self { port: 0, pin }
+    }
+
+    pub const fn p1(pin: u8) -> Self {
+        Self { port: 1, pin }
+    }
+
+    /// Returns the combined port and pin number as used by the PSEL registers
+    pub const fn psel(&self) -> u8 {
+        self.port * 32 + self.pin
+    }
+}
+
+/// Which half of the split keyboard the firmware is running on
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum Hand {
+    Left,
+    Right,
+}
+
+impl Hand {
+    pub const fn opposite(&self) -> Self {
+        match self {
+            Hand::Left => Hand::Right,
+            Hand::Right => Hand::Left,
+        }
+    }
+}
+
+/// How the firmware decides at boot which half it is running on
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum HandDetection {
+    /// A strap pin, read with the internal pull-up enabled. The half is
+    /// `Left` when the pin is tied low and `Right` otherwise.
+    Pin(Pin),
+    /// The hand stored in flash by the raw HID
+    /// [`StoreHand`](crate::raw_hid::Command::StoreHand) command. Until one
+    /// is stored, the USB heuristic decides at every boot; its guess is never
+    /// stored, since both halves see USB power right after flashing.
+    Stored { usb_hand: Hand },
+    /// The half powered over USB at boot is `usb_hand`, the other one is the
+    /// opposite.
+    UsbConnected { usb_hand: Hand },
+}
+
+/// Vendor ID of [pid.codes](https://pid.codes), which allocates PIDs to open
+/// hardware projects
+pub const PID_CODES_VID: u16 = 0x1209;
+
+/// The pid.codes PID reserved for testing, a placeholder until the board has
+/// an allocated PID. It must not ship in a released board definition.
+pub const PID_CODES_TEST_PID: u16 = 0x0001;
+
+/// Identity reported in the USB device descriptor. The serial number comes
+/// from the chip's device ID and the product is suffixed with the hand.
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub struct UsbIdentity {
+    /// Vendor ID, also how host tools recognize the keyboard
+    pub vid: u16,
+    /// Product ID, unique for the keyboard under `vid`
+    pub pid: u16,
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    /// Maximum current drawn from the bus in mA
+    pub max_power: u16,
+}
+
+impl UsbIdentity {
+    /// Whether the identity still uses the pid.codes test PID placeholder
+    pub const fn is_placeholder(&self) -> bool {
+        self.vid == PID_CODES_VID && self.pid == PID_CODES_TEST_PID
+    }
+}
+
+/// Declarative description of a keyboard: everything that differs between
+/// controllers and PCBs lives here instead of in the firmware entry point.
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub struct BoardConfig<const N_COLS: usize, const N_ROWS: usize> {
+    pub name: &'static str,
+    pub hand_detection: HandDetection,
+    pub cols: [Pin; N_COLS],
+    pub rows: [Pin; N_ROWS],
+    pub matrix: MatrixConfig,
+    /// Key held while the board starts to enter diagnostic mode, by (row,
+    /// column), or `None` to only enter it from a host tool
+    pub diagnostics_key: Option<(usize, usize)>,
+    /// Time without key presses after which the board enters System OFF,
+    /// or `None` to stay on. The timer is paused while the USB host has
+    /// configured the keyboard.
+    pub sleep_timeout: Option<Duration>,
+    /// Interval at which the host polls the keyboard endpoint in ms. The
+    /// host may round it, full-speed hosts poll at most every 1 ms.
+    pub usb_poll_ms: u8,
+    /// How the battery voltage is measured, or `None` without a battery
+    pub battery: Option<BatteryConfig>,
+    pub usb: UsbIdentity,
+}
+
+impl<const N_COLS: usize, const N_ROWS: usize> BoardConfig<N_COLS, N_ROWS> {
+    /// The same board after checking that no pin is used twice by the
+    /// matrix, the hand strap and the battery input, which the firmware
+    /// would otherwise take twice. Called in the board's `const`, so a
+    /// clash is a build error.
+    pub const fn validated(self) -> Self {
+        let count = N_COLS + N_ROWS + 2;
+        let mut i = 0;
+        while i < count {
+            let mut j = i + 1;
+            while j < count {
+                if let (Some(a), Some(b)) = (self.pin(i), self.pin(j)) {
+                    assert!(
+                        a.psel() != b.psel(),
+                        "pin used twice in the board definition"
+                    );
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        self
+    }
+
+    /// Pins of the board by index: columns, rows, the hand strap, then the
+    /// battery input
+    const fn pin(&self, index: usize) -> Option<Pin> {
+        if index < N_COLS {
+            Some(self.cols[index])
+        } else if index < N_COLS + N_ROWS {
+            Some(self.rows[index - N_COLS])
+        } else if index == N_COLS + N_ROWS {
+            match self.hand_detection {
+                HandDetection::Pin(pin) => Some(pin),
+                _ => None,
+            }
+        } else {
+            match self.battery {
+                Some(battery) => battery.input.pin(),
+                None => None,
+            }
+        }
+    }
+}
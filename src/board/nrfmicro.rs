@@ -0,0 +1,61 @@
+//! [nrfMicro](https://github.com/joric/nrfmicro/wiki/Pinout) wired as a
+//! 5x6 Dactyl Manuform half, scanned as a 7-column by 6-row matrix.
+
+use embassy_time::Duration;
+
+use super::{
+    BoardConfig, Hand, HandDetection, PID_CODES_TEST_PID, PID_CODES_VID, Pin, UsbIdentity,
+};
+use crate::{
+    battery::{BatteryConfig, BatteryInput},
+    matrix::{ActiveLevel, DiodeDirection, Ghosting, MatrixConfig},
+};
+
+pub const BOARD: BoardConfig<7, 6> = BoardConfig {
+    name: "nrfMicro",
+    hand_detection: HandDetection::Stored {
+        usb_hand: Hand::Left,
+    },
+    cols: [
+        Pin::p0(31), // col 0
+        Pin::p0(29), // col 1
+        Pin::p0(2),  // col 2
+        Pin::p1(13), // col 3
+        Pin::p0(3),  // col 4
+        Pin::p0(28), // col 5
+        Pin::p1(11), // col 6
+    ],
+    rows: [
+        Pin::p0(20), // row 0
+        Pin::p0(13), // row 1
+        Pin::p0(24), // row 2
+        Pin::p0(9),  // row 3
+        Pin::p0(10), // row 4
+        Pin::p1(6),  // row 5
+    ],
+    matrix: MatrixConfig {
+        diode_direction: DiodeDirection::Col2Row,
+        active_level: ActiveLevel::High,
+        ghosting: Ghosting::Trust,
+        scan_interval: Duration::from_millis(5),
+        idle_timeout: Some(Duration::from_millis(500)),
+    },
+    // Q on the left half, Y on the right
+    diagnostics_key: Some((1, 1)),
+    sleep_timeout: Some(Duration::from_secs(15 * 60)),
+    usb_poll_ms: 1,
+    battery: Some(BatteryConfig {
+        input: BatteryInput::VddhDiv5,
+        divider: (5, 1),
+    }),
+    usb: UsbIdentity {
+        // PLACEHOLDER: the pid.codes test PID, replace it with an allocated
+        // PID before releasing the board
+        vid: PID_CODES_VID,
+        pid: PID_CODES_TEST_PID,
+        manufacturer: "German Arutyunov",
+        product: "Dactyl Manuform",
+        max_power: 100,
+    },
+}
+.validated();
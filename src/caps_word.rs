@@ -0,0 +1,127 @@
+//! Caps Word: typing a single word in capitals without Caps Lock.
+//!
+//! Tapping [`Extra::CapsWord`](crate::keycodes::Extra::CapsWord) shifts the
+//! letters typed after it, along with the keys listed as
+//! [`CapsWordConfig::shifted`], so `-` types `_` for `SOME_CONSTANT`. Keys
+//! listed as [`CapsWordConfig::continue_keys`] are typed as mapped and keep
+//! the word going, and any other key ends it, as does a pause longer than
+//! [`CapsWordConfig::timeout`]. Modifier, layer and one-shot keys neither
+//! continue nor end it.
+//!
+//! The shift is added to each key as a [`KeyCode::Modified`] key, so it only
+//! lasts while the key is held and never touches modifier keys held on their
+//! own. While the host has Caps Lock on, letters are already capitals and are
+//! sent without the shift.
+
+use embassy_time::{Duration, Instant};
+
+use crate::keycodes::{KeyCode, KeyboardUsage, Mods};
+
+/// Which keys Caps Word shifts and which keep it going
+#[derive(Copy, Debug, Clone, Eq, PartialEq)]
+pub struct CapsWordConfig {
+    /// Pause after which Caps Word ends on its own
+    pub timeout: Duration,
+    /// Keys shifted besides the letters
+    pub shifted: &'static [KeyboardUsage],
+    /// Keys typed as mapped without ending the word
+    pub continue_keys: &'static [KeyboardUsage],
+}
+
+impl CapsWordConfig {
+    /// QMK's defaults: `-` becomes `_`, digits, Backspace and Delete continue
+    /// the word
+    pub const DEFAULT: Self = Self {
+        timeout: Duration::from_secs(5),
+        shifted: &[KeyboardUsage::KeyboardDashUnderscore],
+        continue_keys: &[
+            KeyboardUsage::Keyboard1Exclamation,
+            KeyboardUsage::Keyboard2At,
+            KeyboardUsage::Keyboard3Hash,
+            KeyboardUsage::Keyboard4Dollar,
+            KeyboardUsage::Keyboard5Percent,
+            KeyboardUsage::Keyboard6Caret,
+            KeyboardUsage::Keyboard7Ampersand,
+            KeyboardUsage::Keyboard8Asterisk,
+            KeyboardUsage::Keyboard9OpenParens,
+            KeyboardUsage::Keyboard0CloseParens,
+            KeyboardUsage::KeyboardBackspace,
+            KeyboardUsage::KeyboardDelete,
+        ],
+    };
+}
+
+impl Default for CapsWordConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// State of Caps Word in a [`KeyProcessor`](crate::processor::KeyProcessor)
+#[derive(Copy, Debug, Clone)]
+pub struct CapsWord {
+    config: CapsWordConfig,
+    active: bool,
+    /// When the word was started or last continued
+    last_at: Instant,
+}
+
+impl CapsWord {
+    pub const fn new(config: CapsWordConfig) -> Self {
+        Self {
+            config,
+            active: false,
+            last_at: Instant::MIN,
+        }
+    }
+
+    /// Whether Caps Word is on at `at`
+    pub fn is_active(&self, at: Instant) -> bool {
+        self.active && at.saturating_duration_since(self.last_at) <= self.config.timeout
+    }
+
+    pub fn toggle(&mut self, at: Instant) {
+        self.active = !self.is_active(at);
+        self.last_at = at;
+    }
+
+    /// Keycode to send for a key pressed at `at` while the `held` modifiers
+    /// are down, with Shift added if Caps Word shifts it. Ends Caps Word for
+    /// keys not part of a word.
+    pub fn apply(&mut self, keycode: KeyCode, held: Mods, caps_lock: bool, at: Instant) -> KeyCode {
+        if !self.is_active(at) {
+            self.active = false;
+            return keycode;
+        }
+
+        let (mods, usage) = match keycode {
+            KeyCode::Base(usage) => (Mods::NONE, usage),
+            KeyCode::Modified(mods, usage) => (mods, usage),
+            _ => return keycode,
+        };
+        // Modifier keys wait for the key they are held for
+        if keycode.to_hid_values().1 == 0 {
+            return keycode;
+        }
+        // Shortcuts like Ctrl+C are not part of a word
+        let shift = Mods::LSFT.union(Mods::RSFT);
+        if !mods.union(held).difference(shift).is_empty() {
+            self.active = false;
+            return keycode;
+        }
+
+        let letter = (KeyboardUsage::KeyboardAa as u8..=KeyboardUsage::KeyboardZz as u8)
+            .contains(&(usage as u8));
+        self.last_at = at;
+        if letter && caps_lock {
+            keycode
+        } else if letter || self.config.shifted.contains(&usage) {
+            keycode.with_mods(Mods::LSFT)
+        } else if self.config.continue_keys.contains(&usage) {
+            keycode
+        } else {
+            self.active = false;
+            keycode
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use defmt::Format;
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    channel::Channel,
+};
+
+use crate::matrix::KeyEvent;
+
+/// Most stuck switches a [`SelfTest`] lists, further ones are only counted
+pub const MAX_STUCK: usize = 8;
+
+/// Most rows or columns the shorted line masks of a [`SelfTest`] can hold
+pub const MAX_LINES: usize = u16::BITS as usize;
+
+/// Wiring faults found by [`Matrix::self_test`](crate::matrix::Matrix::self_test)
+/// when the board starts
+#[derive(Copy, Debug, Clone, Default, Eq, PartialEq, Format)]
+pub struct SelfTest {
+    stuck: [(u8, u8); MAX_STUCK],
+    /// How many of `stuck` are set, which can fall short of `stuck_count`
+    listed: u8,
+    stuck_count: u8,
+    /// Rows that read active while not driven or closed on every switch, as
+    /// a bit mask by row index
+    pub shorted_rows: u16,
+    /// Same as `shorted_rows` for the columns
+    pub shorted_cols: u16,
+}
+
+impl SelfTest {
+    pub const fn new() -> Self {
+        Self {
+            stuck: [(0, 0); MAX_STUCK],
+            listed: 0,
+            stuck_count: 0,
+            shorted_rows: 0,
+            shorted_cols: 0,
+        }
+    }
+
+    pub fn add_stuck(&mut self, row: usize, col: usize) {
+        if let Some(slot) = self.stuck.get_mut(usize::from(self.listed)) {
+            *slot = (row as u8, col as u8);
+            self.listed += 1;
+        }
+        self.stuck_count = self.stuck_count.saturating_add(1);
+    }
+
+    /// Removes a switch from the stuck ones, returning whether it was
+    /// closed. Used for keys that are held at boot on purpose. Once more
+    /// switches are stuck than listed, the freed slot stays empty, as the
+    /// unlisted ones are not known.
+    pub fn take_stuck(&mut self, row: usize, col: usize) -> bool {
+        let Some(index) = self
+            .stuck()
+            .iter()
+            .position(|&key| key == (row as u8, col as u8))
+        else {
+            return false;
+        };
+        self.stuck
+            .copy_within(index + 1..usize::from(self.listed), index);
+        self.listed -= 1;
+        self.stuck[usize::from(self.listed)] = (0, 0);
+        self.stuck_count -= 1;
+        true
+    }
+
+    /// Stuck switches by (row, column), at most [`MAX_STUCK`] of them
+    pub fn stuck(&self) -> &[(u8, u8)] {
+        &self.stuck[..usize::from(self.listed)]
+    }
+
+    /// Number of stuck switches, including those not listed
+    pub fn stuck_count(&self) -> u8 {
+        self.stuck_count
+    }
+
+    /// Marks a row as shorted, ignoring rows past [`MAX_LINES`]
+    pub fn add_shorted_row(&mut self, row: usize) {
+        self.shorted_rows |= line_bit(row);
+    }
+
+    /// Marks a column as shorted, ignoring columns past [`MAX_LINES`]
+    pub fn add_shorted_col(&mut self, col: usize) {
+        self.shorted_cols |= line_bit(col);
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.stuck_count == 0 && self.shorted_rows == 0 && self.shorted_cols == 0
+    }
+}
+
+/// Bit of a line in a shorted line mask, 0 for one past [`MAX_LINES`]
+fn line_bit(index: usize) -> u16 {
+    u32::try_from(index)
+        .ok()
+        .and_then(|index| 1u16.checked_shl(index))
+        .unwrap_or(0)
+}
+
+/// Counters of conditions that slow down or lose input, and the state of the
+/// diagnostic mode, readable by host tools
+pub struct Diagnostics {
+    /// Times the matrix had to wait for the keyboard task because the event
+    /// queue was full
+    queue_full: AtomicU32,
+    /// Key presses that did not fit in the report because too many keys were
+    /// already held
+    rollover: AtomicU32,
+    /// Keys that completed a ghost rectangle, hinting at a missing or
+    /// reversed diode
+    ghosts: AtomicU32,
+    /// Whether switch events are reported to host tools instead of typed
+    diagnostic_mode: AtomicBool,
+    self_test: Mutex<CriticalSectionRawMutex, Cell<SelfTest>>,
+    /// Switch events waiting to be streamed in diagnostic mode
+    switches: Channel<CriticalSectionRawMutex, KeyEvent, 8>,
+}
+
+/// Snapshot of the [`Diagnostics`] counters
+#[derive(Copy, Debug, Clone, Default, Eq, PartialEq, Format)]
+pub struct Counters {
+    pub queue_full: u32,
+    pub rollover: u32,
+    pub ghosts: u32,
+}
+
+impl Diagnostics {
+    pub const fn new() -> Self {
+        Self {
+            queue_full: AtomicU32::new(0),
+            rollover: AtomicU32::new(0),
+            ghosts: AtomicU32::new(0),
+            diagnostic_mode: AtomicBool::new(false),
+            self_test: Mutex::new(Cell::new(SelfTest::new())),
+            switches: Channel::new(),
+        }
+    }
+
+    pub fn record_queue_full(&self) {
+        self.queue_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rollover(&self) {
+        self.rollover.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_ghost(&self) {
+        self.ghosts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counters(&self) -> Counters {
+        Counters {
+            queue_full: self.queue_full.load(Ordering::Relaxed),
+            rollover: self.rollover.load(Ordering::Relaxed),
+            ghosts: self.ghosts.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn diagnostic_mode(&self) -> bool {
+        self.diagnostic_mode.load(Ordering::Relaxed)
+    }
+
+    pub fn set_diagnostic_mode(&self, enabled: bool) {
+        self.diagnostic_mode.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            self.switches.clear();
+        }
+    }
+
+    pub fn record_self_test(&self, report: SelfTest) {
+        self.self_test.lock(|cell| cell.set(report));
+    }
+
+    /// Result of the self-test at boot
+    pub fn self_test(&self) -> SelfTest {
+        self.self_test.lock(Cell::get)
+    }
+
+    /// Queues a switch event for host tools. Events are dropped while no
+    /// tool reads them, the log still has them.
+    pub fn report_switch(&self, event: KeyEvent) {
+        let _ = self.switches.try_send(event);
+    }
+
+    /// Waits for the next switch event to stream
+    pub async fn next_switch(&self) -> KeyEvent {
+        self.switches.receive().await
+    }
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,227 @@
+//! Guessing the host OS from how it enumerates the keyboard.
+//!
+//! Hosts read the string descriptors with request lengths typical for their
+//! USB stack: Linux always asks for 255 bytes, Windows also reads the
+//! language IDs with 4 bytes, and macOS reads the 2-byte header of each
+//! string before the string itself. [`FingerprintDriver`] wraps the USB
+//! driver to see every setup packet, including the standard requests
+//! `embassy-usb` answers on its own, and records them in a
+//! [`HostFingerprint`]. The guess is a heuristic: hosts not matching any
+//! pattern, like a BIOS or a KVM switch, give `None`.
+
+use core::cell::Cell;
+
+use defmt::Format;
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_usb::driver::{ControlPipe, Driver, EndpointAllocError, EndpointError, EndpointType};
+
+use crate::os::HostOs;
+
+const DEVICE_TO_HOST_STANDARD: u8 = 0x80;
+const GET_DESCRIPTOR: u8 = 0x06;
+const STRING_DESCRIPTOR: u8 = 0x03;
+
+/// String descriptor requests seen during an enumeration, counted by the
+/// length the host asked for
+#[derive(Copy, Debug, Clone, Default, Eq, PartialEq, Format)]
+pub struct Fingerprint {
+    strings: u8,
+    len_2: u8,
+    len_4: u8,
+    len_255: u8,
+}
+
+impl Fingerprint {
+    pub const fn new() -> Self {
+        Self {
+            strings: 0,
+            len_2: 0,
+            len_4: 0,
+            len_255: 0,
+        }
+    }
+
+    /// Counts a setup packet if it reads a string descriptor
+    pub fn record(&mut self, setup: &[u8; 8]) {
+        let [request_type, request, _index, descriptor, _, _, length_low, length_high] = *setup;
+        if request_type != DEVICE_TO_HOST_STANDARD
+            || request != GET_DESCRIPTOR
+            || descriptor != STRING_DESCRIPTOR
+        {
+            return;
+        }
+
+        self.strings = self.strings.saturating_add(1);
+        let count = match u16::from_le_bytes([length_low, length_high]) {
+            2 => &mut self.len_2,
+            4 => &mut self.len_4,
+            255 => &mut self.len_255,
+            _ => return,
+        };
+        *count = count.saturating_add(1);
+    }
+
+    pub fn guess(&self) -> Option<HostOs> {
+        if self.len_255 >= 2 && self.len_4 >= 1 {
+            Some(HostOs::Windows)
+        } else if self.strings > 0 && self.strings == self.len_255 {
+            Some(HostOs::Linux)
+        } else if self.len_2 >= 2 && self.len_255 == 0 {
+            Some(HostOs::MacOs)
+        } else {
+            None
+        }
+    }
+}
+
+/// Fingerprint of the current enumeration, shared between the control pipe,
+/// the USB event handler and the task making the guess
+pub struct HostFingerprint {
+    fingerprint: Mutex<CriticalSectionRawMutex, Cell<Fingerprint>>,
+    configured: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl HostFingerprint {
+    pub const fn new() -> Self {
+        Self {
+            fingerprint: Mutex::new(Cell::new(Fingerprint::new())),
+            configured: Signal::new(),
+        }
+    }
+
+    pub fn record(&self, setup: &[u8; 8]) {
+        self.fingerprint.lock(|cell| {
+            let mut fingerprint = cell.get();
+            fingerprint.record(setup);
+            cell.set(fingerprint);
+        });
+    }
+
+    /// Forgets the requests seen so far, when a new enumeration starts
+    pub fn clear(&self) {
+        self.fingerprint.lock(|cell| cell.set(Fingerprint::new()));
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint.lock(Cell::get)
+    }
+
+    pub fn guess(&self) -> Option<HostOs> {
+        self.fingerprint().guess()
+    }
+
+    /// Marks the enumeration as complete
+    pub fn set_configured(&self) {
+        self.configured.signal(());
+    }
+
+    /// Waits until the host configured the device. Hosts keep reading
+    /// strings for a while after that, so wait a little longer before
+    /// guessing.
+    pub async fn wait_configured(&self) {
+        self.configured.wait().await
+    }
+}
+
+impl Default for HostFingerprint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// USB driver recording every setup packet in a [`HostFingerprint`] and
+/// otherwise passing everything through to the wrapped driver
+pub struct FingerprintDriver<'f, D> {
+    driver: D,
+    fingerprint: &'f HostFingerprint,
+}
+
+impl<'f, D> FingerprintDriver<'f, D> {
+    pub fn new(driver: D, fingerprint: &'f HostFingerprint) -> Self {
+        Self {
+            driver,
+            fingerprint,
+        }
+    }
+}
+
+impl<'d, 'f: 'd, D: Driver<'d>> Driver<'d> for FingerprintDriver<'f, D> {
+    type EndpointOut = D::EndpointOut;
+    type EndpointIn = D::EndpointIn;
+    type ControlPipe = FingerprintControlPipe<'f, D::ControlPipe>;
+    type Bus = D::Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.driver
+            .alloc_endpoint_out(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.driver
+            .alloc_endpoint_in(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        let (bus, pipe) = self.driver.start(control_max_packet_size);
+        let pipe = FingerprintControlPipe {
+            pipe,
+            fingerprint: self.fingerprint,
+        };
+        (bus, pipe)
+    }
+}
+
+pub struct FingerprintControlPipe<'f, C> {
+    pipe: C,
+    fingerprint: &'f HostFingerprint,
+}
+
+impl<C: ControlPipe> ControlPipe for FingerprintControlPipe<'_, C> {
+    fn max_packet_size(&self) -> usize {
+        self.pipe.max_packet_size()
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        let setup = self.pipe.setup().await;
+        self.fingerprint.record(&setup);
+        setup
+    }
+
+    async fn data_out(
+        &mut self,
+        buf: &mut [u8],
+        first: bool,
+        last: bool,
+    ) -> Result<usize, EndpointError> {
+        self.pipe.data_out(buf, first, last).await
+    }
+
+    async fn data_in(&mut self, data: &[u8], first: bool, last: bool) -> Result<(), EndpointError> {
+        self.pipe.data_in(data, first, last).await
+    }
+
+    async fn accept(&mut self) {
+        self.pipe.accept().await
+    }
+
+    async fn reject(&mut self) {
+        self.pipe.reject().await
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        self.pipe.accept_set_address(addr).await
+    }
+}
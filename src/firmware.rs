@@ -0,0 +1,557 @@
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use defmt::{info, unwrap, warn};
+use embassy_futures::{
+    join::{join4, join5},
+    select::{Either, select},
+};
+use embassy_nrf::{
+    Peripherals, bind_interrupts,
+    gpio::{AnyPin, Input, Level, Output, OutputDrive, Pull},
+    nvmc::Nvmc,
+    pac, peripherals,
+    saadc::{self, ChannelConfig, Input as _, Saadc},
+    usb as nrf_usb,
+};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::{Channel, Sender, TrySendError},
+    signal::Signal,
+};
+use embassy_time::{Delay, Instant, Timer};
+
+use crate::{
+    battery::{BatteryInput, BatteryState, LevelText},
+    board::{BoardConfig, Hand, HandDetection, Pin},
+    diagnostics::Diagnostics,
+    fingerprint::{FingerprintDriver, HostFingerprint},
+    identity::{FIRMWARE_VERSION, ProductString, SerialNumber},
+    keycodes::Extra,
+    layout::{CAPS_WORD, Layers},
+    matrix::{ActiveLevel, DiodeDirection, KeyEvent, Matrix, MatrixConfig, MatrixError},
+    os::OsState,
+    physical::PhysicalKey,
+    power,
+    processor::{Effect, KeyProcessor},
+    raw_hid::{self, RawHidHandler},
+    storage::Storage,
+    usb::{self, HidError, HostLeds, UsbHandler, UsbKeyboard, UsbRequestHandler},
+};
+
+bind_interrupts!(struct Irqs {
+    USBD => nrf_usb::InterruptHandler<peripherals::USBD>;
+    CLOCK_POWER => nrf_usb::vbus_detect::InterruptHandler;
+    SAADC => saadc::InterruptHandler;
+});
+
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+static USB_CONFIGURED: AtomicBool = AtomicBool::new(false);
+static KEY_CHANNEL: Channel<CriticalSectionRawMutex, TimedEvent, 16> = Channel::new();
+static ACTIVITY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static BATTERY: BatteryState = BatteryState::new();
+static DIAGNOSTICS: Diagnostics = Diagnostics::new();
+static OS: OsState = OsState::new();
+static FINGERPRINT: HostFingerprint = HostFingerprint::new();
+static LEDS: HostLeds = HostLeds::new();
+/// Layer state of the keyboard task, saved before entering System OFF
+static TOGGLED_LAYERS: AtomicU32 = AtomicU32::new(0);
+static LOCKED_LAYERS: AtomicU32 = AtomicU32::new(0);
+
+impl Pin {
+    /// Takes the GPIO described by this pin.
+    ///
+    /// # Safety
+    ///
+    /// The pin must not be in use anywhere else, which holds as long as the
+    /// board definition lists every pin once and nothing else takes it from
+    /// [`Peripherals`].
+    unsafe fn steal(&self) -> AnyPin {
+        unsafe { AnyPin::steal(self.psel()) }
+    }
+}
+
+/// Reads the factory-programmed 64-bit device ID from FICR
+fn device_id() -> u64 {
+    let low = pac::FICR.deviceid(0).read() as u64;
+    let high = pac::FICR.deviceid(1).read() as u64;
+    (high << 32) | low
+}
+
+fn usb_connected() -> bool {
+    pac::POWER.usbregstatus().read().vbusdetect()
+}
+
+/// Guesses the hand from whether the half is powered over USB
+fn hand_from_usb(usb_hand: Hand) -> Hand {
+    if usb_connected() {
+        usb_hand
+    } else {
+        usb_hand.opposite()
+    }
+}
+
+/// Determines which half the firmware is running on
+async fn detect_hand(detection: HandDetection, storage: &mut Storage<'_>) -> Hand {
+    match detection {
+        HandDetection::Pin(pin) => {
+            let strap = Input::new(unsafe { pin.steal() }, Pull::Up);
+            // Let the pull-up settle before sampling
+            Timer::after_micros(10).await;
+            if strap.is_low() {
+                Hand::Left
+            } else {
+                Hand::Right
+            }
+        }
+        HandDetection::Stored { usb_hand } => match storage.load().hand {
+            Some(hand) => hand,
+            None => {
+                info!("No stored hand, guessing from USB power");
+                hand_from_usb(usb_hand)
+            }
+        },
+        HandDetection::UsbConnected { usb_hand } => hand_from_usb(usb_hand),
+    }
+}
+
+/// Configures the SAADC channel the battery is measured on
+fn battery_channel(input: BatteryInput) -> Option<ChannelConfig<'static>> {
+    let input = match input {
+        BatteryInput::VddhDiv5 => return Some(ChannelConfig::single_ended(saadc::VddhDiv5Input)),
+        // Safety: the analog input is only used by the battery monitor
+        BatteryInput::Ain(n) => unsafe {
+            match n {
+                0 => peripherals::P0_02::steal().degrade_saadc(),
+                1 => peripherals::P0_03::steal().degrade_saadc(),
+                2 => peripherals::P0_04::steal().degrade_saadc(),
+                3 => peripherals::P0_05::steal().degrade_saadc(),
+                4 => peripherals::P0_28::steal().degrade_saadc(),
+                5 => peripherals::P0_29::steal().degrade_saadc(),
+                6 => peripherals::P0_30::steal().degrade_saadc(),
+                7 => peripherals::P0_31::steal().degrade_saadc(),
+                _ => {
+                    warn!("No analog input AIN{}", n);
+                    return None;
+                }
+            }
+        },
+    };
+    Some(ChannelConfig::single_ended(input))
+}
+
+/// A key event on its way from the matrix to the keyboard task
+struct TimedEvent {
+    event: KeyEvent,
+    /// When the scan saw the switch change, at most one scan interval after
+    /// the physical press or release
+    detected_at: Instant,
+}
+
+type NrfMatrix<const N_OUT: usize, const N_IN: usize> =
+    Matrix<Output<'static>, Input<'static>, Delay, N_OUT, N_IN>;
+
+/// Configures the matrix pins for the scan direction and active level
+fn new_matrix<const N_OUT: usize, const N_IN: usize>(
+    outputs: [Pin; N_OUT],
+    inputs: [Pin; N_IN],
+    config: MatrixConfig,
+) -> NrfMatrix<N_OUT, N_IN> {
+    let (idle, pull) = match config.active_level {
+        ActiveLevel::High => (Level::Low, Pull::Down),
+        ActiveLevel::Low => (Level::High, Pull::Up),
+    };
+
+    let outputs =
+        outputs.map(|pin| Output::new(unsafe { pin.steal() }, idle, OutputDrive::Standard));
+    let inputs = inputs.map(|pin| Input::new(unsafe { pin.steal() }, pull));
+    Matrix::new(outputs, inputs, Delay, config)
+}
+
+/// Runs the matrix self-test and enters diagnostic mode if the board's
+/// diagnostics key is held
+async fn self_test<const N_OUT: usize, const N_IN: usize>(
+    matrix: &mut NrfMatrix<N_OUT, N_IN>,
+    diagnostics_key: Option<(usize, usize)>,
+) {
+    let mut report = match matrix.self_test().await {
+        Ok(report) => report,
+        Err(e) => {
+            warn!("Matrix self-test failed: {:?}", e);
+            return;
+        }
+    };
+
+    if let Some((row, col)) = diagnostics_key
+        && report.take_stuck(row, col)
+    {
+        info!("Diagnostics key held, entering diagnostic mode");
+        DIAGNOSTICS.set_diagnostic_mode(true);
+    }
+    if report.is_clean() {
+        info!("Matrix self-test passed");
+    } else {
+        warn!("Matrix self-test: {:?}", report);
+    }
+    DIAGNOSTICS.record_self_test(report);
+}
+
+/// Scans the matrix forever, sending key events to the keyboard task. A key
+/// pressed while the bus is suspended also wakes up the host and is still
+/// sent, so its release never arrives alone. Every press counts as activity
+/// for the sleep timeout.
+///
+/// A full queue holds back the scan until the keyboard task catches up, so no
+/// event is ever dropped.
+///
+/// In diagnostic mode presses are logged and streamed to host tools instead,
+/// and so are the releases of those presses, even after the mode is left.
+/// Releases of keys held when the mode was entered still reach the keyboard
+/// task, so they do not stay down.
+async fn scan_matrix<const N_OUT: usize, const N_IN: usize>(
+    mut matrix: NrfMatrix<N_OUT, N_IN>,
+    diagnostics_key: Option<(usize, usize)>,
+    key_sender: Sender<'static, CriticalSectionRawMutex, TimedEvent, 16>,
+    remote_wakeup: &Signal<CriticalSectionRawMutex, ()>,
+) {
+    self_test(&mut matrix, diagnostics_key).await;
+
+    let config = matrix.config();
+    // Presses kept from the keyboard task in diagnostic mode, by output and
+    // input line, whose releases must be kept from it too
+    let mut swallowed = [[false; N_IN]; N_OUT];
+    loop {
+        if let Err(e) = matrix.wait_while_idle().await {
+            warn!("Waiting for a key press failed: {:?}", e);
+        }
+
+        let result = matrix
+            .scan_keys(async |event| {
+                if event.pressed {
+                    ACTIVITY.signal(());
+                }
+
+                let (output, input) = config.lines(event.row, event.col);
+                if DIAGNOSTICS.diagnostic_mode() || swallowed[output][input] {
+                    info!(
+                        "Switch at ({}, {}) pressed: {}",
+                        event.row, event.col, event.pressed
+                    );
+                    DIAGNOSTICS.report_switch(event);
+                    if event.pressed || swallowed[output][input] {
+                        swallowed[output][input] = event.pressed;
+                        return;
+                    }
+                }
+
+                if event.pressed && SUSPENDED.load(Ordering::Relaxed) {
+                    info!("Triggering remote wakeup");
+                    remote_wakeup.signal(());
+                }
+
+                let event = TimedEvent {
+                    event,
+                    detected_at: Instant::now(),
+                };
+                if let Err(TrySendError::Full(event)) = key_sender.try_send(event) {
+                    warn!("Key queue full, waiting for the keyboard task");
+                    DIAGNOSTICS.record_queue_full();
+                    key_sender.send(event).await;
+                }
+            })
+            .await;
+        match result {
+            Ok(()) => {}
+            Err(MatrixError::Ghost { row, col }) => {
+                warn!("Ghost key at ({}, {}), check the diodes", row, col);
+                DIAGNOSTICS.record_ghost();
+            }
+            Err(e) => warn!("Matrix scan failed: {:?}", e),
+        }
+    }
+}
+
+/// Runs the keyboard firmware for the given board. The same image runs on
+/// both halves; the hand is detected at boot and selects the layout.
+pub async fn run<const N_COLS: usize, const N_ROWS: usize, const N_LAYERS: usize>(
+    p: Peripherals,
+    board: &BoardConfig<N_COLS, N_ROWS>,
+    get_layout: impl Fn(Hand) -> Layers<N_COLS, N_ROWS, N_LAYERS>,
+    get_physical_keys: impl Fn(Hand) -> &'static [PhysicalKey],
+) {
+    let woke = power::woke_from_system_off();
+    if woke {
+        info!("Woke up from System OFF");
+    }
+
+    let saadc = p.SAADC;
+    let mut storage = Storage::new(Nvmc::new(p.NVMC));
+    let hand = detect_hand(board.hand_detection, &mut storage).await;
+    let serial = SerialNumber::new(device_id());
+    let product = ProductString::new(board.usb.product, hand);
+    info!(
+        "Board: {}, firmware: {}, serial: {}, hand: {:?}",
+        board.name,
+        FIRMWARE_VERSION,
+        serial.as_str(),
+        hand
+    );
+    if board.usb.is_placeholder() {
+        warn!("USB identity uses the pid.codes test PID placeholder");
+    }
+    OS.set_mode(storage.load().os_mode);
+    info!("OS mode: {:?}", OS.mode());
+    let mut processor = KeyProcessor::new(get_layout(hand), &DIAGNOSTICS).with_caps_word(CAPS_WORD);
+    // Layers are only saved on the way into System OFF, after a power cycle
+    // they would be stale
+    if woke {
+        let settings = storage.load();
+        processor.restore_layers(settings.toggled_layers, settings.locked_layers);
+    }
+    // Shared by the keyboard, raw HID and sleep tasks, which never hold it
+    // across an await
+    let storage = RefCell::new(storage);
+
+    // Enable the external high-frequency oscillator (hfosc)
+    // This is necessary for USB to work correctly.
+    // The hfosc is used as the clock source for the USB peripheral.
+    info!("Enabling External HFOSC...");
+    pac::CLOCK.tasks_hfclkstart().write_value(1);
+    while pac::CLOCK.events_hfclkstarted().read() != 1 {}
+    info!("External HFOSC enabled successfully");
+
+    // Initialize USB - try software VBUS detection to bypass hardware issues
+    let driver = embassy_nrf::usb::Driver::new(
+        p.USBD,
+        Irqs,
+        embassy_nrf::usb::vbus_detect::HardwareVbusDetect::new(Irqs),
+    );
+    // Watches enumeration to guess the host OS, see `fingerprint`
+    let driver = FingerprintDriver::new(driver, &FINGERPRINT);
+
+    let config = usb::device_config(&board.usb, product.as_str(), serial.as_str());
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    // LED reports arrive on the OUT endpoint or as control requests
+    let mut request_handler = UsbRequestHandler::new(&LEDS);
+    let mut control_handler = UsbRequestHandler::new(&LEDS);
+    let mut device_handler = UsbHandler::new(&USB_CONFIGURED, &SUSPENDED, &FINGERPRINT);
+
+    let mut state = embassy_usb::class::hid::State::new();
+    let mut raw_hid_state = embassy_usb::class::hid::State::new();
+
+    let mut builder = embassy_usb::Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+
+    builder.handler(&mut device_handler);
+
+    // Create HID class
+    let hid_config = usb::keyboard_hid_config(board.usb_poll_ms, Some(&mut control_handler));
+    let hid = embassy_usb::class::hid::HidReaderWriter::<_, 1, { usb::KEYBOARD_REPORT_LEN }>::new(
+        &mut builder,
+        &mut state,
+        hid_config,
+    );
+
+    // Vendor interface for host tools, see `raw_hid`
+    let raw_hid_config = embassy_usb::class::hid::Config {
+        report_descriptor: raw_hid::REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 10,
+        max_packet_size: raw_hid::REPORT_LEN as u16,
+    };
+    let raw_hid = embassy_usb::class::hid::HidReaderWriter::<
+        _,
+        { raw_hid::REPORT_LEN },
+        { raw_hid::REPORT_LEN },
+    >::new(&mut builder, &mut raw_hid_state, raw_hid_config);
+
+    let mut usb_device = builder.build();
+    let (reader, writer) = hid.split();
+    let (mut raw_hid_reader, mut raw_hid_writer) = raw_hid.split();
+
+    // Initialize keyboard
+    let mut keyboard = UsbKeyboard::new(writer, &USB_CONFIGURED);
+
+    // Create a channel for sending key events from matrix scanner to USB task
+    let key_sender = KEY_CHANNEL.sender();
+    let key_receiver = KEY_CHANNEL.receiver();
+
+    let remote_wakeup: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+    let usb_fut = async {
+        info!("USB task starting...");
+        loop {
+            info!("USB device starting enumeration...");
+            usb_device.run_until_suspend().await;
+            info!("USB device suspended");
+            match select(usb_device.wait_resume(), remote_wakeup.wait()).await {
+                Either::First(_) => {
+                    info!("USB device resumed");
+                }
+                Either::Second(_) => {
+                    info!("Remote wakeup triggered");
+                    unwrap!(usb_device.remote_wakeup().await)
+                }
+            }
+        }
+    };
+
+    let in_fut = async {
+        let config = board.matrix;
+        match config.diode_direction {
+            DiodeDirection::Col2Row => {
+                let matrix = new_matrix(board.cols, board.rows, config);
+                scan_matrix(matrix, board.diagnostics_key, key_sender, &remote_wakeup).await
+            }
+            DiodeDirection::Row2Col => {
+                let matrix = new_matrix(board.rows, board.cols, config);
+                scan_matrix(matrix, board.diagnostics_key, key_sender, &remote_wakeup).await
+            }
+        }
+    };
+
+    let keyboard_fut = async {
+        loop {
+            // Wait for key events from the channel
+            let TimedEvent { event, detected_at } = key_receiver.receive().await;
+            processor.set_os(OS.active());
+            processor.set_caps_lock(LEDS.caps_lock());
+            let effect = processor.process(event, detected_at);
+            TOGGLED_LAYERS.store(processor.toggled_layers(), Ordering::Relaxed);
+            LOCKED_LAYERS.store(processor.locked_layers(), Ordering::Relaxed);
+            match effect {
+                None => {}
+                Some(Effect::Action(Extra::BatteryLevel)) => {
+                    let text = LevelText::new(hand, BATTERY.percentage());
+                    let typed = keyboard
+                        .type_text(text.as_str(), processor.held(), LEDS.caps_lock())
+                        .await;
+                    if let Err(e) = typed {
+                        warn!("Failed to type battery level: {:?}", e);
+                    }
+                }
+                Some(Effect::Action(Extra::NextOsMode)) => {
+                    let mode = OS.mode().next();
+                    info!("OS mode: {:?}", mode);
+                    OS.set_mode(mode);
+                    let stored = storage
+                        .borrow_mut()
+                        .update(|settings| settings.os_mode = mode);
+                    if let Err(e) = stored {
+                        warn!("Failed to store the OS mode: {:?}", e);
+                    }
+                }
+                Some(Effect::Action(action)) => warn!("Unhandled action {:?}", action),
+                // Reports carry every held key, so the next one sent after an
+                // error brings the host back in sync
+                Some(Effect::Report) => match keyboard.send_report(&processor.report()).await {
+                    Ok(written) => {
+                        if cfg!(feature = "latency") {
+                            info!(
+                                "Latency: {} us from matrix edge to report written",
+                                (written - detected_at).as_micros()
+                            );
+                        }
+                    }
+                    Err(HidError::NotConfigured) => {
+                        warn!("USB device not configured, skipping key report")
+                    }
+                    Err(e) => warn!("Failed to send report: {:?}", e),
+                },
+            }
+        }
+    };
+
+    let out_fut = async {
+        reader.run(false, &mut request_handler).await;
+    };
+
+    let sleep_fut = power::sleep_when_idle(board, &ACTIVITY, &USB_CONFIGURED, || {
+        let toggled = TOGGLED_LAYERS.load(Ordering::Relaxed);
+        let locked = LOCKED_LAYERS.load(Ordering::Relaxed);
+        let stored = storage.borrow_mut().update(|settings| {
+            settings.toggled_layers = toggled;
+            settings.locked_layers = locked;
+        });
+        if let Err(e) = stored {
+            warn!("Failed to store the layers: {:?}", e);
+        }
+    });
+
+    let battery_fut = async {
+        if let Some(battery) = board.battery
+            && let Some(channel) = battery_channel(battery.input)
+        {
+            let mut saadc = Saadc::new(saadc, Irqs, saadc::Config::default(), [channel]);
+            power::monitor_battery(&mut saadc, battery, &BATTERY).await
+        }
+    };
+
+    let raw_hid_fut = async {
+        let handler = RawHidHandler::new(&BATTERY, &DIAGNOSTICS, get_physical_keys(hand));
+        let mut request = [0; raw_hid::REPORT_LEN];
+        loop {
+            raw_hid_reader.ready().await;
+            let report =
+                match select(raw_hid_reader.read(&mut request), DIAGNOSTICS.next_switch()).await {
+                    Either::First(Ok(len)) => {
+                        let request = &request[..len];
+                        if let Some(hand) = raw_hid::hand_to_store(request) {
+                            info!("Storing hand {:?}", hand);
+                            let stored = storage
+                                .borrow_mut()
+                                .update(|settings| settings.hand = Some(hand));
+                            if let Err(e) = stored {
+                                warn!("Failed to store the hand: {:?}", e);
+                            }
+                        }
+                        handler.handle(request)
+                    }
+                    Either::First(Err(e)) => {
+                        warn!("Failed to read raw HID request: {:?}", e);
+                        continue;
+                    }
+                    Either::Second(event) => raw_hid::switch_report(event),
+                };
+            if let Err(e) = raw_hid_writer.write(&report).await {
+                warn!("Failed to send raw HID report: {:?}", e);
+            }
+        }
+    };
+
+    let host_os_fut = async {
+        loop {
+            FINGERPRINT.wait_configured().await;
+            // Hosts read the remaining strings right after configuring
+            Timer::after_millis(500).await;
+            let host_os = FINGERPRINT.guess();
+            info!(
+                "Host OS: {:?}, from {:?}",
+                host_os,
+                FINGERPRINT.fingerprint()
+            );
+            OS.set_detected(host_os);
+        }
+    };
+
+    join5(
+        usb_fut,
+        in_fut,
+        keyboard_fut,
+        out_fut,
+        join4(sleep_fut, battery_fut, raw_hid_fut, host_os_fut),
+    )
+    .await;
+}
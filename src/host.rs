@@ -0,0 +1,21 @@
+//! Support for running the keyboard code on the host, shared by the
+//! simulator and the tests.
+
+// Logs from the library go nowhere, host programs print what they need
+#[defmt::global_logger]
+struct NullLogger;
+
+unsafe impl defmt::Logger for NullLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
@@ -0,0 +1,67 @@
+//! Strings and version numbers identifying this keyboard to the host.
+
+use crate::{board::Hand, text::TextBuffer};
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Firmware version as the BCD `bcdDevice` field of the USB device
+/// descriptor, e.g. `0x0102` for 1.2.x
+pub const DEVICE_RELEASE: u16 = (bcd(parse(env!("CARGO_PKG_VERSION_MAJOR"))) << 8)
+    | bcd(parse(env!("CARGO_PKG_VERSION_MINOR")));
+
+const fn parse(digits: &str) -> u16 {
+    let digits = digits.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits[i] - b'0') as u16;
+        i += 1;
+    }
+    value
+}
+
+/// Two BCD digits, so components above 99 wrap
+const fn bcd(value: u16) -> u16 {
+    ((value / 10 % 10) << 4) | (value % 10)
+}
+
+/// Length of a serial number, 16 hex digits of the 64-bit device ID
+const SERIAL_LEN: usize = 16;
+
+/// Longest product string, including the hand suffix
+const PRODUCT_LEN: usize = 64;
+
+/// USB serial number unique to each chip, the factory-programmed 64-bit
+/// device ID in hex
+pub struct SerialNumber(TextBuffer<SERIAL_LEN>);
+
+impl SerialNumber {
+    pub fn new(device_id: u64) -> Self {
+        let mut text = TextBuffer::new();
+        text.push_hex(device_id, SERIAL_LEN as u32);
+        Self(text)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+/// USB product string naming the half, e.g. `Dactyl Manuform (left)`
+pub struct ProductString(TextBuffer<PRODUCT_LEN>);
+
+impl ProductString {
+    pub fn new(product: &str, hand: Hand) -> Self {
+        let mut text = TextBuffer::new();
+        text.push_str(product);
+        text.push_str(match hand {
+            Hand::Left => " (left)",
+            Hand::Right => " (right)",
+        });
+        Self(text)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
@@ -1,5 +1,5 @@
 use defmt::Format;
-use usbd_hid::descriptor::KeyboardUsage;
+pub use usbd_hid::descriptor::KeyboardUsage;
 
 #[repr(u8)]
 #[allow(unused)]
@@ -7,14 +7,94 @@ use usbd_hid::descriptor::KeyboardUsage;
 #[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
 pub enum Extra {
     NA,
+    /// Types the battery level of this half
+    BatteryLevel,
+    /// Falls through to the key at the same position on the next active
+    /// layer below
+    Transparent,
+    /// Switches to the next [`OsMode`](crate::os::OsMode) and stores it
+    NextOsMode,
+    /// Toggles [Caps Word](crate::caps_word)
+    CapsWord,
 }
 
-#[repr(u8)]
+/// Switches between the layers of a keymap, by layer index
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum LayerAction {
+    /// Turns the layer on while the key is held
+    Momentary(u8),
+    /// Turns the layer on or off with every press
+    Toggle(u8),
+}
+
+/// Keys only macOS knows, reported outside the keyboard usage page
 #[allow(unused)]
 #[non_exhaustive]
 #[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
 pub enum MacosKeys {
-    Fn = 0xA4, // Custom scancode for Fn (no standard exists)
+    /// Fn/Globe key of Apple keyboards, usage 0x03 on Apple's vendor page
+    /// 0xFF, see [`usb::KEYBOARD_REPORT_DESCRIPTOR`](crate::usb::KEYBOARD_REPORT_DESCRIPTOR)
+    Fn,
+}
+
+/// Modifier flags, laid out like the modifier byte of a keyboard report
+#[derive(Copy, Debug, Clone, Default, Eq, PartialEq, Format)]
+pub struct Mods(u8);
+
+impl Mods {
+    pub const NONE: Self = Self(0);
+    pub const LCTL: Self = Self(1 << 0);
+    pub const LSFT: Self = Self(1 << 1);
+    pub const LALT: Self = Self(1 << 2);
+    pub const LGUI: Self = Self(1 << 3);
+    pub const RCTL: Self = Self(1 << 4);
+    pub const RSFT: Self = Self(1 << 5);
+    pub const RALT: Self = Self(1 << 6);
+    pub const RGUI: Self = Self(1 << 7);
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Both sets of modifiers, usable in a `const`
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// These modifiers without those in `other`
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl core::ops::BitOr for Mods {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+/// Keys that apply to the next key typed after a tap, and act like their
+/// plain counterparts while held with other keys
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum OneShot {
+    /// Modifiers added to the next key
+    Mods(Mods),
+    /// Layer the next key is looked up on, by layer index
+    Layer(u8),
 }
 
 #[repr(u8)]
@@ -25,15 +105,33 @@ pub enum KeyCode {
     Base(KeyboardUsage),
     Macos(MacosKeys),
     Extra(Extra),
+    Layer(LayerAction),
+    /// A key typed with modifiers added, such as Ctrl+C or Shift+9 for `(`.
+    /// The modifiers only apply while the key is held and leave modifier keys
+    /// held on their own alone.
+    Modified(Mods, KeyboardUsage),
+    OneShot(OneShot),
 }
 
 impl KeyCode {
+    /// The same key with `mods` added, usable in a `const`. Only keys of the
+    /// keyboard usage page can carry modifiers.
+    pub const fn with_mods(self, mods: Mods) -> Self {
+        match self {
+            KeyCode::Base(usage) => KeyCode::Modified(mods, usage),
+            KeyCode::Modified(held, usage) => KeyCode::Modified(held.union(mods), usage),
+            _ => panic!("only keyboard keys can be combined with modifiers"),
+        }
+    }
+
     /// Converts a KeyCode to its corresponding HID usage code
     pub fn to_usage_code(&self) -> u8 {
         match self {
-            KeyCode::Base(usage) => *usage as u8,
-            KeyCode::Macos(macos_key) => *macos_key as u8,
-            KeyCode::Extra(extra) => *extra as u8,
+            KeyCode::Base(usage) | KeyCode::Modified(_, usage) => *usage as u8,
+            // macOS keys have their own bits in the report, extra, layer and
+            // one-shot keys are handled by the firmware and never reach the
+            // host
+            KeyCode::Macos(_) | KeyCode::Extra(_) | KeyCode::Layer(_) | KeyCode::OneShot(_) => 0,
         }
     }
 
@@ -41,14 +139,47 @@ impl KeyCode {
     /// modifier byte and normal key values for the HID report
     pub fn to_hid_values(&self) -> (u8, u8) {
         let keycode = self.to_usage_code();
+        let mods = match self {
+            KeyCode::Modified(mods, _) => mods.bits(),
+            _ => 0,
+        };
 
-        if keycode >= 0xE0 && keycode <= 0xE7 {
+        if (0xE0..=0xE7).contains(&keycode) {
             // It's a modifier key
             let modifier_bit = 1 << (keycode - 0xE0);
-            (modifier_bit, 0)
+            (mods | modifier_bit, 0)
         } else {
             // It's a normal key
-            (0, keycode)
+            (mods, keycode)
         }
     }
 }
+
+/// Left Shift bit in the modifier byte of a keyboard report
+const LEFT_SHIFT: u8 = 1 << 1;
+
+/// Returns the modifier byte and usage code typing `c` on a US layout, or
+/// `None` for characters without a key
+pub fn ascii_to_hid(c: char) -> Option<(u8, u8)> {
+    let hid = match c {
+        'a'..='z' => (0, KeyboardUsage::KeyboardAa as u8 + (c as u8 - b'a')),
+        'A'..='Z' => (
+            LEFT_SHIFT,
+            KeyboardUsage::KeyboardAa as u8 + (c as u8 - b'A'),
+        ),
+        '1'..='9' => (
+            0,
+            KeyboardUsage::Keyboard1Exclamation as u8 + (c as u8 - b'1'),
+        ),
+        '0' => (0, KeyboardUsage::Keyboard0CloseParens as u8),
+        ' ' => (0, KeyboardUsage::KeyboardSpacebar as u8),
+        '-' => (0, KeyboardUsage::KeyboardDashUnderscore as u8),
+        '.' => (0, KeyboardUsage::KeyboardPeriodGreater as u8),
+        '/' => (0, KeyboardUsage::KeyboardSlashQuestion as u8),
+        '?' => (LEFT_SHIFT, KeyboardUsage::KeyboardSlashQuestion as u8),
+        ':' => (LEFT_SHIFT, KeyboardUsage::KeyboardSemiColon as u8),
+        '%' => (LEFT_SHIFT, KeyboardUsage::Keyboard5Percent as u8),
+        _ => return None,
+    };
+    Some(hid)
+}
@@ -0,0 +1,229 @@
+//! Keymap syntax that reads like the board.
+//!
+//! [`keymap!`](crate::keymap!) takes one bracketed grid per layer and
+//! [`kc!`](crate::kc!) turns a single QMK-style key name into a
+//! [`KeyCode`](crate::keycodes::KeyCode). Keys are separated by whitespace
+//! only, so a grid can be laid out like the physical half. Every layer has
+//! one key per physical key, in the order of the half's
+//! [`PhysicalLayout`](crate::physical::PhysicalLayout).
+
+/// Keymap with one layer per bracketed grid of QMK-style key names, see
+/// [`kc!`](crate::kc!) for the names. The result is an
+/// array of [`Keymap`](crate::physical::Keymap)s, so a layer with the wrong
+/// number of keys fails the build, and
+/// [`matrix_layers`](crate::physical::matrix_layers) in a `const` rejects
+/// layer keys for missing layers.
+///
+/// ```
+/// use dactyl_rs::{keymap, physical::Keymap};
+///
+/// const KEYMAP: [Keymap<4>; 2] = keymap! {
+///     [
+///         Q       W
+///         LSFT    MO(1)
+///     ]
+///     [
+///         1       2
+///         _______ _______
+///     ]
+/// };
+/// ```
+#[macro_export]
+macro_rules! keymap {
+    ($([$($layer:tt)*])+) => {
+        [$($crate::keymap!(@keys [] $($layer)*)),+]
+    };
+
+    (@keys [$($keys:expr,)*]) => {
+        [$($keys),*]
+    };
+    (@keys [$($keys:expr,)*] $name:ident($($args:tt)*) $($rest:tt)*) => {
+        $crate::keymap!(@keys [$($keys,)* $crate::kc!($name($($args)*)),] $($rest)*)
+    };
+    (@keys [$($keys:expr,)*] $key:tt $($rest:tt)*) => {
+        $crate::keymap!(@keys [$($keys,)* $crate::kc!($key),] $($rest)*)
+    };
+}
+
+/// [`KeyCode`](crate::keycodes::KeyCode) for a QMK-style key name: letters,
+/// digits, `F1` to `F12`, the names QMK uses for the other keys of a US
+/// keyboard (`ESC`, `SPC`, `LSFT`, `SCLN`, ...), `BATT` for
+/// [`Extra::BatteryLevel`](crate::keycodes::Extra::BatteryLevel), `AP_GLOB` for
+/// the Apple Fn/Globe key, `OS_NEXT` to switch the
+/// [`OsMode`](crate::os::OsMode), `CW_TOGG` for
+/// [Caps Word](crate::caps_word), `XXXXXXX` for
+/// no key and `_______` for a key that falls through to the layer below.
+///
+/// `MO(n)` and `TG(n)` switch to layer `n` while held or until pressed again.
+/// `OSM(LSFT)` or `OSM(LCTL | LSFT)` and `OSL(n)` are one-shot keys: tapped,
+/// the modifiers or layer `n` apply to the next key only.
+/// A modifier name wrapping a key, like `LCTL(C)` or `LCTL(LSFT(T))`, types
+/// the key with the modifiers added, as does `C`, `S`, `A` or `G` for the
+/// left ones; shifted symbols have their own names (`LPRN` is `LSFT(9)`).
+///
+/// Any other name fails the build:
+///
+/// ```compile_fail
+/// // error: unknown key name `LSHFT`
+/// let key = dactyl_rs::kc!(LSHFT);
+/// ```
+#[macro_export]
+macro_rules! kc {
+    (XXXXXXX) => { $crate::keycodes::KeyCode::Extra($crate::keycodes::Extra::NA) };
+    (_______) => { $crate::keycodes::KeyCode::Extra($crate::keycodes::Extra::Transparent) };
+    (BATT) => { $crate::keycodes::KeyCode::Extra($crate::keycodes::Extra::BatteryLevel) };
+    (OS_NEXT) => { $crate::keycodes::KeyCode::Extra($crate::keycodes::Extra::NextOsMode) };
+    (CW_TOGG) => { $crate::keycodes::KeyCode::Extra($crate::keycodes::Extra::CapsWord) };
+    (AP_GLOB) => { $crate::keycodes::KeyCode::Macos($crate::keycodes::MacosKeys::Fn) };
+
+    (MO($layer:literal)) => {
+        $crate::keycodes::KeyCode::Layer($crate::keycodes::LayerAction::Momentary($layer))
+    };
+    (TG($layer:literal)) => {
+        $crate::keycodes::KeyCode::Layer($crate::keycodes::LayerAction::Toggle($layer))
+    };
+
+    (OSM($($mods:ident)|+)) => {
+        $crate::keycodes::KeyCode::OneShot($crate::keycodes::OneShot::Mods(
+            $crate::keycodes::Mods::NONE$(.union($crate::keycodes::Mods::$mods))+
+        ))
+    };
+    (OSL($layer:literal)) => {
+        $crate::keycodes::KeyCode::OneShot($crate::keycodes::OneShot::Layer($layer))
+    };
+
+    (LCTL($($key:tt)+)) => { $crate::kc!(@mods LCTL $($key)+) };
+    (LSFT($($key:tt)+)) => { $crate::kc!(@mods LSFT $($key)+) };
+    (LALT($($key:tt)+)) => { $crate::kc!(@mods LALT $($key)+) };
+    (LGUI($($key:tt)+)) => { $crate::kc!(@mods LGUI $($key)+) };
+    (RCTL($($key:tt)+)) => { $crate::kc!(@mods RCTL $($key)+) };
+    (RSFT($($key:tt)+)) => { $crate::kc!(@mods RSFT $($key)+) };
+    (RALT($($key:tt)+)) => { $crate::kc!(@mods RALT $($key)+) };
+    (RGUI($($key:tt)+)) => { $crate::kc!(@mods RGUI $($key)+) };
+    (C($($key:tt)+)) => { $crate::kc!(@mods LCTL $($key)+) };
+    (S($($key:tt)+)) => { $crate::kc!(@mods LSFT $($key)+) };
+    (A($($key:tt)+)) => { $crate::kc!(@mods LALT $($key)+) };
+    (G($($key:tt)+)) => { $crate::kc!(@mods LGUI $($key)+) };
+
+    (A) => { $crate::kc!(@usage KeyboardAa) };
+    (B) => { $crate::kc!(@usage KeyboardBb) };
+    (C) => { $crate::kc!(@usage KeyboardCc) };
+    (D) => { $crate::kc!(@usage KeyboardDd) };
+    (E) => { $crate::kc!(@usage KeyboardEe) };
+    (F) => { $crate::kc!(@usage KeyboardFf) };
+    (G) => { $crate::kc!(@usage KeyboardGg) };
+    (H) => { $crate::kc!(@usage KeyboardHh) };
+    (I) => { $crate::kc!(@usage KeyboardIi) };
+    (J) => { $crate::kc!(@usage KeyboardJj) };
+    (K) => { $crate::kc!(@usage KeyboardKk) };
+    (L) => { $crate::kc!(@usage KeyboardLl) };
+    (M) => { $crate::kc!(@usage KeyboardMm) };
+    (N) => { $crate::kc!(@usage KeyboardNn) };
+    (O) => { $crate::kc!(@usage KeyboardOo) };
+    (P) => { $crate::kc!(@usage KeyboardPp) };
+    (Q) => { $crate::kc!(@usage KeyboardQq) };
+    (R) => { $crate::kc!(@usage KeyboardRr) };
+    (S) => { $crate::kc!(@usage KeyboardSs) };
+    (T) => { $crate::kc!(@usage KeyboardTt) };
+    (U) => { $crate::kc!(@usage KeyboardUu) };
+    (V) => { $crate::kc!(@usage KeyboardVv) };
+    (W) => { $crate::kc!(@usage KeyboardWw) };
+    (X) => { $crate::kc!(@usage KeyboardXx) };
+    (Y) => { $crate::kc!(@usage KeyboardYy) };
+    (Z) => { $crate::kc!(@usage KeyboardZz) };
+
+    (1) => { $crate::kc!(@usage Keyboard1Exclamation) };
+    (2) => { $crate::kc!(@usage Keyboard2At) };
+    (3) => { $crate::kc!(@usage Keyboard3Hash) };
+    (4) => { $crate::kc!(@usage Keyboard4Dollar) };
+    (5) => { $crate::kc!(@usage Keyboard5Percent) };
+    (6) => { $crate::kc!(@usage Keyboard6Caret) };
+    (7) => { $crate::kc!(@usage Keyboard7Ampersand) };
+    (8) => { $crate::kc!(@usage Keyboard8Asterisk) };
+    (9) => { $crate::kc!(@usage Keyboard9OpenParens) };
+    (0) => { $crate::kc!(@usage Keyboard0CloseParens) };
+
+    (ENT) => { $crate::kc!(@usage KeyboardEnter) };
+    (ESC) => { $crate::kc!(@usage KeyboardEscape) };
+    (BSPC) => { $crate::kc!(@usage KeyboardBackspace) };
+    (TAB) => { $crate::kc!(@usage KeyboardTab) };
+    (SPC) => { $crate::kc!(@usage KeyboardSpacebar) };
+    (MINS) => { $crate::kc!(@usage KeyboardDashUnderscore) };
+    (EQL) => { $crate::kc!(@usage KeyboardEqualPlus) };
+    (LBRC) => { $crate::kc!(@usage KeyboardOpenBracketBrace) };
+    (RBRC) => { $crate::kc!(@usage KeyboardCloseBracketBrace) };
+    (BSLS) => { $crate::kc!(@usage KeyboardBackslashBar) };
+    (SCLN) => { $crate::kc!(@usage KeyboardSemiColon) };
+    (QUOT) => { $crate::kc!(@usage KeyboardSingleDoubleQuote) };
+    (GRV) => { $crate::kc!(@usage KeyboardBacktickTilde) };
+    (COMM) => { $crate::kc!(@usage KeyboardCommaLess) };
+    (DOT) => { $crate::kc!(@usage KeyboardPeriodGreater) };
+    (SLSH) => { $crate::kc!(@usage KeyboardSlashQuestion) };
+    (CAPS) => { $crate::kc!(@usage KeyboardCapsLock) };
+
+    (F1) => { $crate::kc!(@usage KeyboardF1) };
+    (F2) => { $crate::kc!(@usage KeyboardF2) };
+    (F3) => { $crate::kc!(@usage KeyboardF3) };
+    (F4) => { $crate::kc!(@usage KeyboardF4) };
+    (F5) => { $crate::kc!(@usage KeyboardF5) };
+    (F6) => { $crate::kc!(@usage KeyboardF6) };
+    (F7) => { $crate::kc!(@usage KeyboardF7) };
+    (F8) => { $crate::kc!(@usage KeyboardF8) };
+    (F9) => { $crate::kc!(@usage KeyboardF9) };
+    (F10) => { $crate::kc!(@usage KeyboardF10) };
+    (F11) => { $crate::kc!(@usage KeyboardF11) };
+    (F12) => { $crate::kc!(@usage KeyboardF12) };
+
+    (PSCR) => { $crate::kc!(@usage KeyboardPrintScreen) };
+    (INS) => { $crate::kc!(@usage KeyboardInsert) };
+    (DEL) => { $crate::kc!(@usage KeyboardDelete) };
+    (HOME) => { $crate::kc!(@usage KeyboardHome) };
+    (END) => { $crate::kc!(@usage KeyboardEnd) };
+    (PGUP) => { $crate::kc!(@usage KeyboardPageUp) };
+    (PGDN) => { $crate::kc!(@usage KeyboardPageDown) };
+    (LEFT) => { $crate::kc!(@usage KeyboardLeftArrow) };
+    (DOWN) => { $crate::kc!(@usage KeyboardDownArrow) };
+    (UP) => { $crate::kc!(@usage KeyboardUpArrow) };
+    (RGHT) => { $crate::kc!(@usage KeyboardRightArrow) };
+
+    (LCTL) => { $crate::kc!(@usage KeyboardLeftControl) };
+    (LSFT) => { $crate::kc!(@usage KeyboardLeftShift) };
+    (LALT) => { $crate::kc!(@usage KeyboardLeftAlt) };
+    (LGUI) => { $crate::kc!(@usage KeyboardLeftGUI) };
+    (RCTL) => { $crate::kc!(@usage KeyboardRightControl) };
+    (RSFT) => { $crate::kc!(@usage KeyboardRightShift) };
+    (RALT) => { $crate::kc!(@usage KeyboardRightAlt) };
+    (RGUI) => { $crate::kc!(@usage KeyboardRightGUI) };
+
+    (EXLM) => { $crate::kc!(LSFT(1)) };
+    (AT) => { $crate::kc!(LSFT(2)) };
+    (HASH) => { $crate::kc!(LSFT(3)) };
+    (DLR) => { $crate::kc!(LSFT(4)) };
+    (PERC) => { $crate::kc!(LSFT(5)) };
+    (CIRC) => { $crate::kc!(LSFT(6)) };
+    (AMPR) => { $crate::kc!(LSFT(7)) };
+    (ASTR) => { $crate::kc!(LSFT(8)) };
+    (LPRN) => { $crate::kc!(LSFT(9)) };
+    (RPRN) => { $crate::kc!(LSFT(0)) };
+    (UNDS) => { $crate::kc!(LSFT(MINS)) };
+    (PLUS) => { $crate::kc!(LSFT(EQL)) };
+    (LCBR) => { $crate::kc!(LSFT(LBRC)) };
+    (RCBR) => { $crate::kc!(LSFT(RBRC)) };
+    (PIPE) => { $crate::kc!(LSFT(BSLS)) };
+    (COLN) => { $crate::kc!(LSFT(SCLN)) };
+    (DQUO) => { $crate::kc!(LSFT(QUOT)) };
+    (TILD) => { $crate::kc!(LSFT(GRV)) };
+    (LABK) => { $crate::kc!(LSFT(COMM)) };
+    (RABK) => { $crate::kc!(LSFT(DOT)) };
+    (QUES) => { $crate::kc!(LSFT(SLSH)) };
+
+    (@mods $mods:ident $($key:tt)+) => {
+        $crate::kc!($($key)+).with_mods($crate::keycodes::Mods::$mods)
+    };
+    (@usage $usage:ident) => {
+        $crate::keycodes::KeyCode::Base($crate::keycodes::KeyboardUsage::$usage)
+    };
+    ($($other:tt)*) => {
+        compile_error!(concat!("unknown key name `", stringify!($($other)*), "`"))
+    };
+}
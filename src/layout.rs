@@ -1,40 +1,110 @@
-use usbd_hid::descriptor::KeyboardUsage;
-
-use crate::keycodes::{Extra, KeyCode};
+use crate::{
+    board::Hand,
+    caps_word::CapsWordConfig,
+    keycodes::KeyCode,
+    physical::{Keymap, PhysicalKey, PhysicalLayout, matrix_layers},
+};
 
 pub type Layout<const N_COLS: usize, const N_ROWS: usize> = [[KeyCode; N_COLS]; N_ROWS];
 
-// Macros for shorter keycode definitions
-macro_rules! k {
-    ($key:ident) => {
-        KeyCode::Base(KeyboardUsage::$key)
-    };
-}
+/// Most layers a keymap can have, one bit each in the processor's layer state
+pub const MAX_LAYERS: usize = 32;
+
+/// Layers of a keymap placed into the matrix, lowest first
+pub type Layers<const N_COLS: usize, const N_ROWS: usize, const N_LAYERS: usize> =
+    [Layout<N_COLS, N_ROWS>; N_LAYERS];
 
-macro_rules! na {
-    () => {
-        KeyCode::Extra(Extra::NA)
+macro_rules! key {
+    ($row:expr, $col:expr, $x:expr, $y:expr) => {
+        PhysicalKey::new($row, $col, $x, $y)
     };
 }
 
-pub fn get_left_layout() -> Layout<7, 6> {
+/// Keys of the left half of a 5x6 Dactyl Manuform, row by row from the
+/// outer column, then the thumb cluster
+pub const LEFT_KEYS: PhysicalLayout<32> = [
+    // number row
+    key!(0, 0, 0.0, 0.5), key!(0, 1, 1.0, 0.5), key!(0, 2, 2.0, 0.25), key!(0, 3, 3.0, 0.0), key!(0, 4, 4.0, 0.25), key!(0, 5, 5.0, 0.375),
+    // top row
+    key!(1, 0, 0.0, 1.5), key!(1, 1, 1.0, 1.5), key!(1, 2, 2.0, 1.25), key!(1, 3, 3.0, 1.0), key!(1, 4, 4.0, 1.25), key!(1, 5, 5.0, 1.375),
+    // home row
+    key!(2, 0, 0.0, 2.5), key!(2, 1, 1.0, 2.5), key!(2, 2, 2.0, 2.25), key!(2, 3, 3.0, 2.0), key!(2, 4, 4.0, 2.25), key!(2, 5, 5.0, 2.375),
+    // bottom row
+    key!(3, 0, 0.0, 3.5), key!(3, 1, 1.0, 3.5), key!(3, 2, 2.0, 3.25), key!(3, 3, 3.0, 3.0), key!(3, 4, 4.0, 3.25), key!(3, 5, 5.0, 3.375),
+    // lowest row
+    key!(4, 2, 2.0, 4.25), key!(4, 3, 3.0, 4.0),
+    // thumb cluster
+    key!(4, 5, 5.25, 5.0).rotated(15.0), key!(4, 4, 6.25, 5.25).rotated(15.0),
+    key!(5, 5, 5.5, 6.0).rotated(30.0), key!(5, 4, 6.5, 6.25).rotated(30.0),
+    key!(5, 3, 6.0, 7.0).rotated(45.0), key!(5, 2, 7.0, 7.25).rotated(45.0),
+];
+
+/// Keys of the right half, the mirror image of [`LEFT_KEYS`] listed from the
+/// inner column
+pub const RIGHT_KEYS: PhysicalLayout<32> = [
+    // number row
+    key!(0, 1, 2.0, 0.375), key!(0, 2, 3.0, 0.25), key!(0, 3, 4.0, 0.0), key!(0, 4, 5.0, 0.25), key!(0, 5, 6.0, 0.5), key!(0, 6, 7.0, 0.5),
+    // top row
+    key!(1, 1, 2.0, 1.375), key!(1, 2, 3.0, 1.25), key!(1, 3, 4.0, 1.0), key!(1, 4, 5.0, 1.25), key!(1, 5, 6.0, 1.5), key!(1, 6, 7.0, 1.5),
+    // home row
+    key!(2, 1, 2.0, 2.375), key!(2, 2, 3.0, 2.25), key!(2, 3, 4.0, 2.0), key!(2, 4, 5.0, 2.25), key!(2, 5, 6.0, 2.5), key!(2, 6, 7.0, 2.5),
+    // bottom row
+    key!(3, 1, 2.0, 3.375), key!(3, 2, 3.0, 3.25), key!(3, 3, 4.0, 3.0), key!(3, 4, 5.0, 3.25), key!(3, 5, 6.0, 3.5), key!(3, 6, 7.0, 3.5),
+    // lowest row
+    key!(4, 3, 4.0, 4.0), key!(4, 4, 5.0, 4.25),
+    // thumb cluster
+    key!(4, 2, 0.75, 5.25).rotated(-15.0), key!(4, 1, 1.75, 5.0).rotated(-15.0),
+    key!(5, 2, 0.5, 6.25).rotated(-30.0), key!(5, 1, 1.5, 6.0).rotated(-30.0),
+    key!(5, 4, 0.0, 7.25).rotated(-45.0), key!(5, 3, 1.0, 7.0).rotated(-45.0),
+];
+
+/// Keymap of the left half, in the order of [`LEFT_KEYS`]
+pub const LEFT_KEYMAP: [Keymap<32>; 1] = crate::keymap! {
     [
-        [na!(), na!(), na!(), na!(), na!(), na!(), na!()],
-        [na!(), k!(KeyboardQq), k!(KeyboardWw), k!(KeyboardEe), k!(KeyboardRr), k!(KeyboardTt), na!()],
-        [na!(), k!(KeyboardAa), k!(KeyboardSs), k!(KeyboardDd), k!(KeyboardFf), k!(KeyboardGg), na!()],
-        [na!(), k!(KeyboardZz), k!(KeyboardXx), k!(KeyboardCc), k!(KeyboardVv), k!(KeyboardBb), na!()],
-        [na!(), na!(), na!(), na!(), na!(), na!(), na!()],
-        [na!(), na!(), na!(), na!(), na!(), na!(), na!()],
+        XXXXXXX XXXXXXX XXXXXXX XXXXXXX XXXXXXX XXXXXXX
+        XXXXXXX Q       W       E       R       T
+        XXXXXXX A       S       D       F       G
+        XXXXXXX Z       X       C       V       B
+                        XXXXXXX XXXXXXX
+                                        XXXXXXX XXXXXXX
+                                        XXXXXXX XXXXXXX
+                                        XXXXXXX XXXXXXX
     ]
-}
+};
 
-pub fn get_right_layout() -> Layout<7, 6> {
+/// Keymap of the right half, in the order of [`RIGHT_KEYS`]
+pub const RIGHT_KEYMAP: [Keymap<32>; 1] = crate::keymap! {
     [
-        [na!(), na!(), na!(), na!(), na!(), na!(), na!()],
-        [na!(), k!(KeyboardYy), k!(KeyboardUu), k!(KeyboardIi), k!(KeyboardOo), k!(KeyboardPp), na!()],
-        [na!(), k!(KeyboardHh), k!(KeyboardJj), k!(KeyboardKk), k!(KeyboardLl), na!(), na!()],
-        [na!(), k!(KeyboardNn), k!(KeyboardMm), k!(KeyboardCommaLess), k!(KeyboardPeriodGreater), k!(KeyboardSlashQuestion), na!()],
-        [na!(), na!(), na!(), na!(), na!(), na!(), na!()],
-        [na!(), na!(), na!(), na!(), na!(), na!(), na!()],
+        XXXXXXX XXXXXXX XXXXXXX XXXXXXX XXXXXXX XXXXXXX
+        Y       U       I       O       P       XXXXXXX
+        H       J       K       L       XXXXXXX XXXXXXX
+        N       M       COMM    DOT     SLSH    XXXXXXX
+                        XXXXXXX XXXXXXX
+        XXXXXXX XXXXXXX
+        XXXXXXX XXXXXXX
+        XXXXXXX XXXXXXX
     ]
+};
+
+pub const LEFT_LAYERS: Layers<7, 6, 1> = matrix_layers(&LEFT_KEYS, &LEFT_KEYMAP);
+
+pub const RIGHT_LAYERS: Layers<7, 6, 1> = matrix_layers(&RIGHT_KEYS, &RIGHT_KEYMAP);
+
+/// Keys [Caps Word](crate::caps_word) shifts and keeps the word going with,
+/// the same for both halves
+pub const CAPS_WORD: CapsWordConfig = CapsWordConfig::DEFAULT;
+
+pub fn get_layout(hand: Hand) -> Layers<7, 6, 1> {
+    match hand {
+        Hand::Left => LEFT_LAYERS,
+        Hand::Right => RIGHT_LAYERS,
+    }
+}
+
+/// Physical keys of the given half, for host tools
+pub fn get_physical_keys(hand: Hand) -> &'static [PhysicalKey] {
+    match hand {
+        Hand::Left => &LEFT_KEYS,
+        Hand::Right => &RIGHT_KEYS,
+    }
 }
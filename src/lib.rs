@@ -1,8 +1,30 @@
 #![no_std]
 
+pub mod battery;
+pub mod board;
+pub mod caps_word;
+pub mod diagnostics;
+pub mod fingerprint;
+#[cfg(feature = "nrf")]
+pub mod firmware;
+#[cfg(feature = "sim")]
+mod host;
+pub mod identity;
 pub mod keycodes;
+pub mod keymap;
 pub mod layout;
 pub mod matrix;
+pub mod os;
+pub mod physical;
+#[cfg(feature = "nrf")]
+pub mod power;
+pub mod processor;
+pub mod raw_hid;
+pub mod report;
+pub mod settings;
+#[cfg(feature = "nrf")]
+pub mod storage;
+pub mod text;
 pub mod usb;
 
 pub use keycodes::KeyCode;
@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+use dactyl_rs::{
+    board::nrfmicro::BOARD,
+    firmware,
+    layout::{get_layout, get_physical_keys},
+};
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use panic_probe as _;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_nrf::init(Default::default());
+
+    // Add early logging to test defmt
+    defmt::info!("=== Dactyl keyboard firmware starting ===");
+
+    firmware::run(p, &BOARD, get_layout, get_physical_keys).await;
+}
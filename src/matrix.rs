@@ -1,60 +1,459 @@
-use defmt::info;
-use embassy_nrf::gpio::{Input, Output};
-use embassy_time::Timer;
+use defmt::{Format, debug, warn};
+use embassy_futures::select::select_array;
+use embassy_time::{Duration, Instant};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
 
-use crate::{keycodes::KeyCode, layout::Layout};
+use crate::diagnostics::{MAX_LINES, SelfTest};
 
-pub struct Matrix<'a, const N_COLS: usize, const N_ROWS: usize> {
-    cols: [Output<'a>; N_COLS],
-    rows: [Input<'a>; N_ROWS],
-    previous_state: [[bool; N_COLS]; N_ROWS],
+/// Direction of the current through the switch diodes
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum DiodeDirection {
+    /// Columns are driven and rows are read
+    Col2Row,
+    /// Rows are driven and columns are read
+    Row2Col,
 }
 
-impl<'a, const N_COLS: usize, const N_ROWS: usize> Matrix<'a, N_COLS, N_ROWS> {
-    pub fn new(cols: [Output<'a>; N_COLS], rows: [Input<'a>; N_ROWS]) -> Self {
+/// Level of a driven line and of a closed switch on the line read back
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum ActiveLevel {
+    /// Lines are driven high and read with pull-downs
+    High,
+    /// Lines are driven low and read with pull-ups
+    Low,
+}
+
+/// What the scanner does about ghosting: with a missing or reversed diode,
+/// three closed switches on the corners of a rectangle close a path that reads
+/// as the fourth corner too
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum Ghosting {
+    /// Every sample is a real key, as on a matrix with working diodes where
+    /// any combination of keys can be held
+    Trust,
+    /// Report the key completing a rectangle as pressed, but fail the scan
+    /// with [`MatrixError::Ghost`] so the wiring fault gets noticed
+    Report,
+    /// Hold back a key that completes a rectangle until the rectangle is
+    /// broken, at the cost of some legitimate four-key combinations
+    Suppress,
+}
+
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub struct MatrixConfig {
+    pub diode_direction: DiodeDirection,
+    pub active_level: ActiveLevel,
+    pub ghosting: Ghosting,
+    /// Pause between two scans of the matrix, which also rides out switch
+    /// bounce
+    pub scan_interval: Duration,
+    /// Quiet period with every key released after which
+    /// [`Matrix::wait_while_idle`] stops scanning and waits for an edge on
+    /// any input instead, or `None` to keep polling
+    pub idle_timeout: Option<Duration>,
+}
+
+impl MatrixConfig {
+    /// Maps an (output, input) line pair to its (row, column) position
+    pub const fn position(&self, output: usize, input: usize) -> (usize, usize) {
+        match self.diode_direction {
+            DiodeDirection::Col2Row => (input, output),
+            DiodeDirection::Row2Col => (output, input),
+        }
+    }
+
+    /// Maps a (row, column) position back to its (output, input) line pair
+    pub const fn lines(&self, row: usize, col: usize) -> (usize, usize) {
+        match self.diode_direction {
+            DiodeDirection::Col2Row => (col, row),
+            DiodeDirection::Row2Col => (row, col),
+        }
+    }
+}
+
+/// A switch changing state, by its (row, column) position in the layout
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub struct KeyEvent {
+    pub row: usize,
+    pub col: usize,
+    pub pressed: bool,
+}
+
+/// State of every switch, indexed by output and input line
+pub type Sample<const N_OUT: usize, const N_IN: usize> = [[bool; N_IN]; N_OUT];
+
+/// Whether the closed switch between `output` and `input` is a corner of a
+/// rectangle of closed switches, which without diodes may be a phantom
+pub fn completes_rectangle<const N_OUT: usize, const N_IN: usize>(
+    sample: &Sample<N_OUT, N_IN>,
+    output: usize,
+    input: usize,
+) -> bool {
+    let Some(line) = sample.get(output) else {
+        return false;
+    };
+
+    sample
+        .iter()
+        .enumerate()
+        .filter(|(o, other)| *o != output && other.get(input).copied().unwrap_or(false))
+        .any(|(_, other)| (0..N_IN).any(|i| i != input && line[i] && other[i]))
+}
+
+/// Switches changed by a full scan, see [`MatrixState::update_all`]
+pub struct ScanChanges<const N_OUT: usize, const N_IN: usize> {
+    /// Switches whose state changed, by output and input line
+    pub changed: Sample<N_OUT, N_IN>,
+    /// First newly closed switch completing a rectangle, by output and input
+    /// line. It is only set when looking for ghosts.
+    pub ghost: Option<(usize, usize)>,
+}
+
+/// Switch state remembered between scans, indexed by output and input line
+pub struct MatrixState<const N_OUT: usize, const N_IN: usize> {
+    previous_state: Sample<N_OUT, N_IN>,
+}
+
+impl<const N_OUT: usize, const N_IN: usize> MatrixState<N_OUT, N_IN> {
+    pub const fn new() -> Self {
         Self {
-            cols,
-            rows,
-            previous_state: [[false; N_COLS]; N_ROWS],
+            previous_state: [[false; N_IN]; N_OUT],
         }
     }
 
-    pub async fn scan_keys<F>(&mut self, layout: &Layout<N_COLS, N_ROWS>, mut on_key_press: F)
+    /// Records a sample of the switch between `output` and `input`, returning
+    /// whether it changed state since the previous scan
+    pub fn update(&mut self, output: usize, input: usize, is_pressed: bool) -> bool {
+        let Some(cell) = self
+            .previous_state
+            .get_mut(output)
+            .and_then(|state| state.get_mut(input))
+        else {
+            return false;
+        };
+
+        let was_pressed = core::mem::replace(cell, is_pressed);
+        is_pressed != was_pressed
+    }
+
+    /// Records a full scan of the matrix. Switches that close while completing
+    /// a rectangle are flagged unless `ghosting` is [`Ghosting::Trust`], and
+    /// kept released with [`Ghosting::Suppress`]. Switches that were already
+    /// pressed are never flagged, so a rectangle only blocks the key that
+    /// closes it.
+    pub fn update_all(
+        &mut self,
+        sample: &Sample<N_OUT, N_IN>,
+        ghosting: Ghosting,
+    ) -> ScanChanges<N_OUT, N_IN> {
+        let mut changes = ScanChanges {
+            changed: [[false; N_IN]; N_OUT],
+            ghost: None,
+        };
+
+        for (output, line) in sample.iter().enumerate() {
+            for (input, closed) in line.iter().enumerate() {
+                let mut is_pressed = *closed;
+                if is_pressed
+                    && ghosting != Ghosting::Trust
+                    && !self.is_pressed(output, input)
+                    && completes_rectangle(sample, output, input)
+                {
+                    changes.ghost.get_or_insert((output, input));
+                    is_pressed = ghosting != Ghosting::Suppress;
+                }
+                changes.changed[output][input] = self.update(output, input, is_pressed);
+            }
+        }
+
+        changes
+    }
+
+    /// Whether the switch between `output` and `input` was closed in the
+    /// last scan
+    pub fn is_pressed(&self, output: usize, input: usize) -> bool {
+        self.previous_state
+            .get(output)
+            .and_then(|state| state.get(input))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Whether any switch was closed in the last scan
+    pub fn any_pressed(&self) -> bool {
+        self.previous_state.iter().flatten().any(|pressed| *pressed)
+    }
+}
+
+impl<const N_OUT: usize, const N_IN: usize> Default for MatrixState<N_OUT, N_IN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Error raised by one of the matrix pins or a fault found while scanning
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum MatrixError<O, I> {
+    Output(O),
+    Input(I),
+    /// A key at (`row`, `col`) completed a rectangle of closed switches,
+    /// pointing at a missing or reversed diode. Only raised once per
+    /// rectangle with [`Ghosting::Report`].
+    Ghost {
+        row: usize,
+        col: usize,
+    },
+}
+
+/// Key matrix scanner over any [`embedded_hal`] GPIO, so it runs on the MCU
+/// pins as well as on I/O expanders or mock pins on the host.
+///
+/// The outputs are driven one at a time and the inputs read back. Which of
+/// them are the columns and which the rows follows from the diode direction
+/// in the [`MatrixConfig`]. Outputs must start out at the inactive level.
+///
+/// With inputs that can wait for an edge, [`Matrix::wait_while_idle`] lets
+/// the scan loop sleep once every key has been released for the configured
+/// idle timeout. It drives all outputs at once and waits until any input
+/// changes, which on the nRF52840 is a GPIOTE port event rather than a timer
+/// wakeup every scan.
+///
+/// Each scan reads the whole matrix before reporting any change, so keys
+/// completing a ghost rectangle can be told apart according to
+/// [`MatrixConfig::ghosting`].
+pub struct Matrix<O, I, D, const N_OUT: usize, const N_IN: usize> {
+    outputs: [O; N_OUT],
+    inputs: [I; N_IN],
+    delay: D,
+    config: MatrixConfig,
+    state: MatrixState<N_OUT, N_IN>,
+    last_activity: Instant,
+    /// Whether the previous scan found a rectangle, to warn once per
+    /// rectangle instead of on every scan while it is held
+    ghosting: bool,
+}
+
+impl<O, I, D, const N_OUT: usize, const N_IN: usize> Matrix<O, I, D, N_OUT, N_IN>
+where
+    O: OutputPin,
+    I: InputPin,
+    D: DelayNs,
+{
+    pub fn new(outputs: [O; N_OUT], inputs: [I; N_IN], delay: D, config: MatrixConfig) -> Self {
+        // The self-test reports shorted lines as bit masks
+        const {
+            assert!(
+                N_OUT <= MAX_LINES && N_IN <= MAX_LINES,
+                "the self-test reports at most 16 rows and columns"
+            );
+        }
+        Self {
+            outputs,
+            inputs,
+            delay,
+            config,
+            state: MatrixState::new(),
+            last_activity: Instant::now(),
+            ghosting: false,
+        }
+    }
+
+    pub fn config(&self) -> MatrixConfig {
+        self.config
+    }
+
+    /// Scans every switch once, handing each press and release to
+    /// `on_event`. The scan waits for `on_event` to finish, so a slow
+    /// consumer holds back the next scan instead of losing events.
+    pub async fn scan_keys<F>(
+        &mut self,
+        mut on_event: F,
+    ) -> Result<(), MatrixError<O::Error, I::Error>>
     where
-        F: FnMut(KeyCode),
+        F: AsyncFnMut(KeyEvent),
     {
-        for (i, col) in self.cols.iter_mut().enumerate() {
-            col.set_high();
-            // Small delay to allow voltage to stabilize
-            Timer::after_micros(10).await;
-
-            for (j, row) in self.rows.iter().enumerate() {
-                let is_pressed = row.is_high();
-                let was_pressed = self
-                    .previous_state
-                    .get(j)
-                    .and_then(|row| row.get(i))
-                    .copied()
-                    .unwrap_or(false);
-
-                // Only trigger on key press (not release or held)
-                if is_pressed && !was_pressed && j < layout.len() && i < layout[j].len() {
-                    let keycode = layout[j][i];
-                    info!("Key pressed at ({}, {}): {:?}", j, i, keycode);
-                    on_key_press(keycode);
+        let sample = self.read().await?;
+
+        let changes = self.state.update_all(&sample, self.config.ghosting);
+        for (i, line) in changes.changed.iter().enumerate() {
+            for (j, _) in line.iter().enumerate().filter(|(_, changed)| **changed) {
+                let (row, col) = self.config.position(i, j);
+                let pressed = self.state.is_pressed(i, j);
+                debug!("Key at ({}, {}) pressed: {}", row, col, pressed);
+                on_event(KeyEvent { row, col, pressed }).await;
+            }
+        }
+
+        let ghost = changes
+            .ghost
+            .filter(|_| !self.ghosting)
+            .map(|(i, j)| self.config.position(i, j));
+        self.ghosting = changes.ghost.is_some();
+        if let Some((row, col)) = ghost
+            && self.config.ghosting == Ghosting::Suppress
+        {
+            warn!(
+                "Holding back key at ({}, {}), it completes a ghost rectangle",
+                row, col
+            );
+        }
+
+        // Held back keys count too, the matrix is not idle while they are held
+        if sample.iter().flatten().any(|closed| *closed) {
+            self.last_activity = Instant::now();
+        }
+
+        // Scan interval - adjust this for responsiveness vs power consumption.
+        // Saturates instead of wrapping for an interval past u32::MAX µs.
+        let interval = u32::try_from(self.config.scan_interval.as_micros()).unwrap_or(u32::MAX);
+        self.delay.delay_us(interval).await;
+
+        match ghost {
+            Some((row, col)) if self.config.ghosting == Ghosting::Report => {
+                Err(MatrixError::Ghost { row, col })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the wiring before scanning starts: inputs that read active
+    /// while no output drives them are shorted, as are lines on which every
+    /// switch reads closed. Any other closed switch is stuck, or held while
+    /// the board starts.
+    pub async fn self_test(&mut self) -> Result<SelfTest, MatrixError<O::Error, I::Error>> {
+        let mut report = SelfTest::new();
+        let mut shorted_inputs = [false; N_IN];
+
+        self.delay.delay_us(10).await;
+        for (input, shorted) in self.inputs.iter_mut().zip(shorted_inputs.iter_mut()) {
+            *shorted = match self.config.active_level {
+                ActiveLevel::High => input.is_high(),
+                ActiveLevel::Low => input.is_low(),
+            }
+            .map_err(MatrixError::Input)?;
+        }
+
+        let sample = self.read().await?;
+        let shorted_outputs = sample.map(|line| N_IN > 1 && line.iter().all(|closed| *closed));
+
+        for (i, line) in sample.iter().enumerate() {
+            for (j, closed) in line.iter().enumerate() {
+                if *closed && !shorted_outputs[i] && !shorted_inputs[j] {
+                    let (row, col) = self.config.position(i, j);
+                    report.add_stuck(row, col);
                 }
+            }
+        }
+        for (i, _) in shorted_outputs.iter().enumerate().filter(|(_, s)| **s) {
+            self.add_shorted_line(&mut report, i, true);
+        }
+        for (j, _) in shorted_inputs.iter().enumerate().filter(|(_, s)| **s) {
+            self.add_shorted_line(&mut report, j, false);
+        }
+
+        Ok(report)
+    }
+
+    fn add_shorted_line(&self, report: &mut SelfTest, index: usize, output: bool) {
+        let is_col = match self.config.diode_direction {
+            DiodeDirection::Col2Row => output,
+            DiodeDirection::Row2Col => !output,
+        };
+        if is_col {
+            report.add_shorted_col(index);
+        } else {
+            report.add_shorted_row(index);
+        }
+    }
 
-                // Update the previous state
-                if let Some(row_state) = self.previous_state.get_mut(j) {
-                    if let Some(cell) = row_state.get_mut(i) {
-                        *cell = is_pressed;
-                    }
+    /// Drives the outputs one at a time and reads back every switch
+    async fn read(&mut self) -> Result<Sample<N_OUT, N_IN>, MatrixError<O::Error, I::Error>> {
+        let active_level = self.config.active_level;
+
+        let mut sample = [[false; N_IN]; N_OUT];
+        for (output, line) in self.outputs.iter_mut().zip(sample.iter_mut()) {
+            match active_level {
+                ActiveLevel::High => output.set_high(),
+                ActiveLevel::Low => output.set_low(),
+            }
+            .map_err(MatrixError::Output)?;
+            // Small delay to allow voltage to stabilize
+            self.delay.delay_us(10).await;
+
+            for (input, closed) in self.inputs.iter_mut().zip(line.iter_mut()) {
+                *closed = match active_level {
+                    ActiveLevel::High => input.is_high(),
+                    ActiveLevel::Low => input.is_low(),
                 }
+                .map_err(MatrixError::Input)?;
+            }
+
+            match active_level {
+                ActiveLevel::High => output.set_low(),
+                ActiveLevel::Low => output.set_high(),
+            }
+            .map_err(MatrixError::Output)?;
+        }
+
+        Ok(sample)
+    }
+}
+
+/// Idle scanning, for inputs that can wait for an edge. Pins without one,
+/// like those of some I/O expanders, keep polling with
+/// [`scan_keys`](Matrix::scan_keys) and need no
+/// [`idle_timeout`](MatrixConfig::idle_timeout).
+impl<O, I, D, const N_OUT: usize, const N_IN: usize> Matrix<O, I, D, N_OUT, N_IN>
+where
+    O: OutputPin,
+    I: InputPin + Wait,
+    D: DelayNs,
+{
+    /// Returns right away while keys were pressed within the
+    /// [`idle_timeout`](MatrixConfig::idle_timeout). Once it has passed,
+    /// waits for a key press before returning, so the next scan picks up the
+    /// waking key without delay.
+    pub async fn wait_while_idle(&mut self) -> Result<(), MatrixError<O::Error, I::Error>> {
+        match self.config.idle_timeout {
+            Some(timeout) if self.last_activity.elapsed() >= timeout => {
+                self.wait_for_activity().await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Drives every output and waits until any input reads active, then
+    /// returns the outputs to the inactive level
+    async fn wait_for_activity(&mut self) -> Result<(), MatrixError<O::Error, I::Error>> {
+        let active_level = self.config.active_level;
+        debug!("Matrix idle, waiting for a key press");
+
+        for output in self.outputs.iter_mut() {
+            match active_level {
+                ActiveLevel::High => output.set_high(),
+                ActiveLevel::Low => output.set_low(),
+            }
+            .map_err(MatrixError::Output)?;
+        }
+        self.delay.delay_us(10).await;
+
+        let (result, _) = match active_level {
+            ActiveLevel::High => select_array(self.inputs.each_mut().map(I::wait_for_high)).await,
+            ActiveLevel::Low => select_array(self.inputs.each_mut().map(I::wait_for_low)).await,
+        };
+        result.map_err(MatrixError::Input)?;
+
+        for output in self.outputs.iter_mut() {
+            match active_level {
+                ActiveLevel::High => output.set_low(),
+                ActiveLevel::Low => output.set_high(),
             }
-            col.set_low();
+            .map_err(MatrixError::Output)?;
         }
+        debug!("Matrix active");
+        self.last_activity = Instant::now();
 
-        // Scan interval - adjust this for responsiveness vs power consumption
-        Timer::after_millis(10).await;
+        Ok(())
     }
 }
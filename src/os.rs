@@ -0,0 +1,183 @@
+//! Translation of keys for the host's operating system.
+//!
+//! Keymaps are written with PC conventions: Ctrl+C copies and Home jumps to
+//! the start of the line. On macOS the same keys are sent with Ctrl and Cmd
+//! swapped, and the shortcuts whose macOS equivalent is not just a swapped
+//! modifier, like word and line navigation, are replaced. Alt is Option on
+//! macOS already and is sent as is.
+
+use core::cell::Cell;
+
+use defmt::Format;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+
+use crate::keycodes::{KeyCode, KeyboardUsage, Mods};
+
+/// Operating system of the USB host
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum HostOs {
+    Linux,
+    Windows,
+    MacOs,
+}
+
+/// Which operating system keys are translated for, persisted in the
+/// [`Settings`](crate::settings::Settings). Linux and Windows get the keys
+/// as mapped, so only macOS has a mode of its own.
+#[derive(Copy, Debug, Clone, Default, Eq, PartialEq, Format)]
+pub enum OsMode {
+    /// Send keys as mapped, whatever the host
+    #[default]
+    Off,
+    /// Follow the host OS detected during enumeration, sending keys as
+    /// mapped while it is unknown
+    Auto,
+    /// Always translate for macOS
+    MacOs,
+}
+
+impl OsMode {
+    /// Mode selected by the next press of
+    /// [`Extra::NextOsMode`](crate::keycodes::Extra::NextOsMode)
+    pub const fn next(self) -> Self {
+        match self {
+            OsMode::Off => OsMode::Auto,
+            OsMode::Auto => OsMode::MacOs,
+            OsMode::MacOs => OsMode::Off,
+        }
+    }
+}
+
+/// Selected OS mode and the host OS detected during enumeration, shared
+/// between the USB stack and the keyboard task
+pub struct OsState {
+    mode: Mutex<CriticalSectionRawMutex, Cell<OsMode>>,
+    detected: Mutex<CriticalSectionRawMutex, Cell<Option<HostOs>>>,
+}
+
+impl OsState {
+    pub const fn new() -> Self {
+        Self {
+            mode: Mutex::new(Cell::new(OsMode::Off)),
+            detected: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    pub fn mode(&self) -> OsMode {
+        self.mode.lock(Cell::get)
+    }
+
+    pub fn set_mode(&self, mode: OsMode) {
+        self.mode.lock(|cell| cell.set(mode));
+    }
+
+    pub fn detected(&self) -> Option<HostOs> {
+        self.detected.lock(Cell::get)
+    }
+
+    pub fn set_detected(&self, os: Option<HostOs>) {
+        self.detected.lock(|cell| cell.set(os));
+    }
+
+    /// OS keys are translated for, `None` to send them as mapped
+    pub fn active(&self) -> Option<HostOs> {
+        match self.mode() {
+            OsMode::Off => None,
+            OsMode::Auto => self.detected(),
+            OsMode::MacOs => Some(HostOs::MacOs),
+        }
+    }
+}
+
+impl Default for OsState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Modifiers to send to `os` for modifiers of the keymap
+pub fn translate_mods(mods: Mods, os: Option<HostOs>) -> Mods {
+    match os {
+        Some(HostOs::MacOs) => Mods::from_bits(swap_ctrl_gui(mods.bits())),
+        _ => mods,
+    }
+}
+
+/// Swaps the Ctrl and GUI bits of both sides in a modifier byte
+const fn swap_ctrl_gui(bits: u8) -> u8 {
+    let ctrl = bits & (Mods::LCTL.bits() | Mods::RCTL.bits());
+    let gui = bits & (Mods::LGUI.bits() | Mods::RGUI.bits());
+    (bits & !(ctrl | gui)) | ctrl << 3 | gui >> 3
+}
+
+/// macOS shortcut for a PC one that differs by more than Ctrl and Cmd
+fn macos_shortcut(keycode: KeyCode) -> Option<KeyCode> {
+    use KeyboardUsage::*;
+
+    let (mods, usage) = match keycode {
+        KeyCode::Base(usage) => (Mods::NONE, usage),
+        KeyCode::Modified(mods, usage) => (mods, usage),
+        _ => return None,
+    };
+    let shortcut = match (mods, usage) {
+        // Line start and end
+        (Mods::NONE, KeyboardHome) => KeyCode::Modified(Mods::LGUI, KeyboardLeftArrow),
+        (Mods::NONE, KeyboardEnd) => KeyCode::Modified(Mods::LGUI, KeyboardRightArrow),
+        // Document start and end
+        (Mods::LCTL, KeyboardHome) => KeyCode::Modified(Mods::LGUI, KeyboardUpArrow),
+        (Mods::LCTL, KeyboardEnd) => KeyCode::Modified(Mods::LGUI, KeyboardDownArrow),
+        // Word navigation and deletion
+        (
+            Mods::LCTL,
+            KeyboardLeftArrow | KeyboardRightArrow | KeyboardBackspace | KeyboardDelete,
+        ) => KeyCode::Modified(Mods::LALT, usage),
+        _ => return None,
+    };
+    Some(shortcut)
+}
+
+/// Keycode to send to `os` for a key of the keymap. Applied on both press
+/// and release so the host sees the same key go up that went down.
+pub fn translate(keycode: KeyCode, os: Option<HostOs>) -> KeyCode {
+    if os != Some(HostOs::MacOs) {
+        return keycode;
+    }
+    if let Some(shortcut) = macos_shortcut(keycode) {
+        return shortcut;
+    }
+
+    match keycode {
+        KeyCode::Base(usage) => {
+            let (modifier, _) = keycode.to_hid_values();
+            match swap_ctrl_gui(modifier) {
+                bits if bits == modifier => keycode,
+                // A modifier key, now the other one of the pair
+                bits => KeyCode::Base(modifier_usage(bits).unwrap_or(usage)),
+            }
+        }
+        KeyCode::Modified(mods, usage) => {
+            let swapped = translate(KeyCode::Base(usage), os);
+            let mods = Mods::from_bits(swap_ctrl_gui(mods.bits()));
+            swapped.with_mods(mods)
+        }
+        _ => keycode,
+    }
+}
+
+/// Modifier key for a modifier byte with a single bit set
+fn modifier_usage(bits: u8) -> Option<KeyboardUsage> {
+    use KeyboardUsage::*;
+
+    let usage = match bits {
+        0x01 => KeyboardLeftControl,
+        0x02 => KeyboardLeftShift,
+        0x04 => KeyboardLeftAlt,
+        0x08 => KeyboardLeftGUI,
+        0x10 => KeyboardRightControl,
+        0x20 => KeyboardRightShift,
+        0x40 => KeyboardRightAlt,
+        0x80 => KeyboardRightGUI,
+        _ => return None,
+    };
+    Some(usage)
+}
@@ -0,0 +1,116 @@
+//! Physical arrangement of the keys on a half.
+//!
+//! A [`PhysicalLayout`] lists every key that exists on the case, with the
+//! matrix position its switch is wired to and where it sits, so keymaps are
+//! written per physical key instead of per matrix cell and tools can draw the
+//! half. Matrix cells without a physical key stay [`Extra::NA`].
+
+use defmt::Format;
+
+use crate::{
+    keycodes::{Extra, KeyCode, LayerAction, OneShot},
+    layout::{Layers, Layout, MAX_LAYERS},
+};
+
+/// A key on the case and the switch it is wired to
+#[derive(Copy, Debug, Clone, PartialEq, Format)]
+pub struct PhysicalKey {
+    /// Matrix row of the switch
+    pub row: usize,
+    /// Matrix column of the switch
+    pub col: usize,
+    /// Distance of the key's left edge from the left of the half, in key
+    /// units (19.05 mm)
+    pub x: f32,
+    /// Distance of the key's top edge from the top of the half, in key units
+    pub y: f32,
+    /// Clockwise rotation about the key's center in degrees
+    pub rotation: f32,
+}
+
+impl PhysicalKey {
+    /// Key wired to (`row`, `col`) with its top-left corner at (`x`, `y`)
+    pub const fn new(row: usize, col: usize, x: f32, y: f32) -> Self {
+        Self {
+            row,
+            col,
+            x,
+            y,
+            rotation: 0.0,
+        }
+    }
+
+    pub const fn rotated(self, rotation: f32) -> Self {
+        Self { rotation, ..self }
+    }
+}
+
+/// Every key of a half, in the order keymaps list them
+pub type PhysicalLayout<const N_KEYS: usize> = [PhysicalKey; N_KEYS];
+
+/// Keycodes by physical key index, see [`PhysicalLayout`]
+pub type Keymap<const N_KEYS: usize> = [KeyCode; N_KEYS];
+
+/// Places a keymap written per physical key into the matrix the scanner
+/// reports events for, leaving cells without a key as [`Extra::NA`].
+///
+/// Evaluated in a `const`, a key outside the matrix or two keys wired to the
+/// same switch fail the build.
+pub const fn matrix_layout<const N_COLS: usize, const N_ROWS: usize, const N_KEYS: usize>(
+    keys: &PhysicalLayout<N_KEYS>,
+    keymap: &Keymap<N_KEYS>,
+) -> Layout<N_COLS, N_ROWS> {
+    let mut layout = [[KeyCode::Extra(Extra::NA); N_COLS]; N_ROWS];
+    // Tracked apart from the keycodes, a key may be mapped to `XXXXXXX`
+    let mut taken = [[false; N_COLS]; N_ROWS];
+    let mut i = 0;
+    while i < N_KEYS {
+        let PhysicalKey { row, col, .. } = keys[i];
+        assert!(
+            row < N_ROWS && col < N_COLS,
+            "physical key outside the matrix"
+        );
+        assert!(!taken[row][col], "two physical keys on the same switch");
+        taken[row][col] = true;
+        layout[row][col] = keymap[i];
+        i += 1;
+    }
+    layout
+}
+
+/// [`matrix_layout`] for every layer of a keymap, also checking that layer
+/// keys only refer to existing layers
+pub const fn matrix_layers<
+    const N_COLS: usize,
+    const N_ROWS: usize,
+    const N_KEYS: usize,
+    const N_LAYERS: usize,
+>(
+    keys: &PhysicalLayout<N_KEYS>,
+    keymaps: &[Keymap<N_KEYS>; N_LAYERS],
+) -> Layers<N_COLS, N_ROWS, N_LAYERS> {
+    assert!(
+        N_LAYERS > 0 && N_LAYERS <= MAX_LAYERS,
+        "unsupported number of layers"
+    );
+
+    let mut layers = [[[KeyCode::Extra(Extra::NA); N_COLS]; N_ROWS]; N_LAYERS];
+    let mut layer = 0;
+    while layer < N_LAYERS {
+        let mut i = 0;
+        while i < N_KEYS {
+            if let KeyCode::Layer(LayerAction::Momentary(target) | LayerAction::Toggle(target))
+            | KeyCode::OneShot(OneShot::Layer(target)) = keymaps[layer][i]
+            {
+                assert!(
+                    (target as usize) < N_LAYERS,
+                    "layer key for a missing layer"
+                );
+            }
+            i += 1;
+        }
+        layers[layer] = matrix_layout(keys, &keymaps[layer]);
+        layer += 1;
+    }
+    layers
+}
@@ -0,0 +1,140 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{debug, info};
+use embassy_futures::select::{Either, select};
+use embassy_nrf::{
+    pac::{
+        self,
+        gpio::{
+            Gpio,
+            vals::{Dir, Drive, Input, Pull, Sense},
+        },
+    },
+    saadc::Saadc,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    battery::{BatteryConfig, BatteryFilter, BatteryState, percentage},
+    board::{BoardConfig, Pin},
+    matrix::{ActiveLevel, DiodeDirection},
+};
+
+/// Time between two battery samples
+const BATTERY_INTERVAL: Duration = Duration::from_secs(30);
+
+fn port(pin: Pin) -> Gpio {
+    match pin.port {
+        0 => pac::P0,
+        _ => pac::P1,
+    }
+}
+
+/// Returns whether the last reset was a wakeup from System OFF, clearing the
+/// reset reason so it is not reported again
+pub fn woke_from_system_off() -> bool {
+    let off = pac::POWER.resetreas().read().off();
+    if off {
+        pac::POWER.resetreas().write(|w| w.set_off(true));
+    }
+    off
+}
+
+/// Drives every matrix output active and arms SENSE on every input, so any
+/// key press raises DETECT and wakes the chip from System OFF
+fn arm_wakeup<const N_COLS: usize, const N_ROWS: usize>(board: &BoardConfig<N_COLS, N_ROWS>) {
+    let (outputs, inputs): (&[Pin], &[Pin]) = match board.matrix.diode_direction {
+        DiodeDirection::Col2Row => (&board.cols, &board.rows),
+        DiodeDirection::Row2Col => (&board.rows, &board.cols),
+    };
+    let (active, pull, sense) = match board.matrix.active_level {
+        ActiveLevel::High => (true, Pull::PULLDOWN, Sense::HIGH),
+        ActiveLevel::Low => (false, Pull::PULLUP, Sense::LOW),
+    };
+
+    for &pin in outputs {
+        let port = port(pin);
+        let n = pin.pin as usize;
+        if active {
+            port.outset().write(|w| w.set_pin(n, true));
+        } else {
+            port.outclr().write(|w| w.set_pin(n, true));
+        }
+        port.pin_cnf(n).write(|w| {
+            w.set_dir(Dir::OUTPUT);
+            w.set_input(Input::DISCONNECT);
+            w.set_drive(Drive::S0S1);
+        });
+    }
+
+    for &pin in inputs {
+        port(pin).pin_cnf(pin.pin as usize).write(|w| {
+            w.set_dir(Dir::INPUT);
+            w.set_input(Input::CONNECT);
+            w.set_pull(pull);
+            w.set_sense(sense);
+        });
+    }
+}
+
+/// Enters System OFF. The chip resets on wakeup, so this never returns and
+/// the firmware boots again, reloading its settings from flash.
+fn system_off<const N_COLS: usize, const N_ROWS: usize>(board: &BoardConfig<N_COLS, N_ROWS>) -> ! {
+    info!("Entering System OFF");
+    arm_wakeup(board);
+    pac::POWER.systemoff().write(|w| w.set_systemoff(true));
+
+    // System OFF is emulated while a debugger is attached, so execution can
+    // continue past the write
+    loop {
+        cortex_m::asm::wfe();
+    }
+}
+
+/// Puts the board into System OFF once no activity has been signalled for
+/// the board's sleep timeout. The timeout restarts while `usb_configured` is
+/// set, since the host then powers the board and expects it to stay up.
+///
+/// `save_state` runs right before, to store what the next boot restores.
+pub async fn sleep_when_idle<const N_COLS: usize, const N_ROWS: usize>(
+    board: &BoardConfig<N_COLS, N_ROWS>,
+    activity: &Signal<CriticalSectionRawMutex, ()>,
+    usb_configured: &AtomicBool,
+    mut save_state: impl FnMut(),
+) {
+    let Some(timeout) = board.sleep_timeout else {
+        return;
+    };
+
+    loop {
+        match select(activity.wait(), Timer::after(timeout)).await {
+            Either::First(_) => {}
+            Either::Second(_) if usb_configured.load(Ordering::Relaxed) => {}
+            Either::Second(_) => {
+                save_state();
+                system_off(board)
+            }
+        }
+    }
+}
+
+/// Samples the battery forever, publishing the smoothed voltage to `state`
+pub async fn monitor_battery(
+    saadc: &mut Saadc<'_, 1>,
+    config: BatteryConfig,
+    state: &BatteryState,
+) {
+    saadc.calibrate().await;
+    let mut filter = BatteryFilter::new();
+
+    loop {
+        let mut sample = [0; 1];
+        saadc.sample(&mut sample).await;
+        let millivolts = filter.update(config.millivolts(sample[0]));
+        state.set(millivolts);
+        debug!("Battery: {} mV, {}%", millivolts, percentage(millivolts));
+
+        Timer::after(BATTERY_INTERVAL).await;
+    }
+}
@@ -0,0 +1,354 @@
+use defmt::{Format, info, warn};
+use embassy_time::{Duration, Instant};
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::{
+    caps_word::{CapsWord, CapsWordConfig},
+    diagnostics::Diagnostics,
+    keycodes::{Extra, KeyCode, LayerAction, Mods, OneShot},
+    layout::Layers,
+    matrix::KeyEvent,
+    os::HostOs,
+    report::ReportBuilder,
+};
+
+/// Time after a tap of a one-shot key in which the next key gets its
+/// modifiers or layer
+pub const ONESHOT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Most time between two taps of a one-shot key that locks it. A slower
+/// second tap arms it again instead.
+pub const ONESHOT_TAPPING_TERM: Duration = Duration::from_millis(300);
+
+/// What the keyboard has to do in response to a key event
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum Effect {
+    /// Send the updated [`KeyProcessor::report`] to the host
+    Report,
+    /// Run a firmware action
+    Action(Extra),
+}
+
+/// Turns matrix events into reports by looking keys up in the active layers
+/// and tracking which of them are held
+pub struct KeyProcessor<'a, const N_COLS: usize, const N_ROWS: usize, const N_LAYERS: usize> {
+    layers: Layers<N_COLS, N_ROWS, N_LAYERS>,
+    /// Layers held by `MO` keys, one bit per layer
+    momentary: u32,
+    /// Layers switched on by `TG` keys
+    toggled: u32,
+    /// Layers of held one-shot keys
+    oneshot_layers: u32,
+    /// One-shot layers locked by a double tap, active until tapped again
+    locked_layers: u32,
+    /// One-shot modifiers and layer waiting for the next key, and when they
+    /// were tapped
+    armed_mods: Mods,
+    armed_layer: Option<u8>,
+    armed_at: Instant,
+    /// One-shot modifiers locked by a double tap, held until tapped again
+    locked_mods: Mods,
+    /// Number of one-shot keys held
+    oneshot_held: u8,
+    /// Whether another key was pressed while one-shot keys were held, which
+    /// makes them act like plain modifiers and layer keys
+    interrupted: bool,
+    /// Whether letters are being shifted, see [`crate::caps_word`]
+    caps_word: CapsWord,
+    /// Whether the host has Caps Lock on
+    caps_lock: bool,
+    /// Keycode each held key was pressed as, after its layer lookup and the
+    /// one-shot and Caps Word modifiers, so it is released as exactly that
+    /// key even if the active layers or the armed modifiers changed in between
+    pressed_as: [[KeyCode; N_COLS]; N_ROWS],
+    report: ReportBuilder,
+    diagnostics: &'a Diagnostics,
+}
+
+impl<'a, const N_COLS: usize, const N_ROWS: usize, const N_LAYERS: usize>
+    KeyProcessor<'a, N_COLS, N_ROWS, N_LAYERS>
+{
+    pub fn new(layers: Layers<N_COLS, N_ROWS, N_LAYERS>, diagnostics: &'a Diagnostics) -> Self {
+        Self {
+            layers,
+            momentary: 0,
+            toggled: 0,
+            oneshot_layers: 0,
+            locked_layers: 0,
+            armed_mods: Mods::NONE,
+            armed_layer: None,
+            armed_at: Instant::MIN,
+            locked_mods: Mods::NONE,
+            oneshot_held: 0,
+            interrupted: false,
+            caps_word: CapsWord::new(CapsWordConfig::DEFAULT),
+            caps_lock: false,
+            pressed_as: [[KeyCode::Extra(Extra::NA); N_COLS]; N_ROWS],
+            report: ReportBuilder::new(),
+            diagnostics,
+        }
+    }
+
+    /// Uses `config` for Caps Word instead of [`CapsWordConfig::DEFAULT`]
+    pub fn with_caps_word(mut self, config: CapsWordConfig) -> Self {
+        self.caps_word = CapsWord::new(config);
+        self
+    }
+
+    /// Tells Caps Word whether the host has Caps Lock on, see
+    /// [`HostLeds`](crate::usb::HostLeds)
+    pub fn set_caps_lock(&mut self, caps_lock: bool) {
+        self.caps_lock = caps_lock;
+    }
+
+    /// Whether keys pressed at `at` are typed in Caps Word
+    pub fn caps_word(&self, at: Instant) -> bool {
+        self.caps_word.is_active(at)
+    }
+
+    /// Translates keys for `os` from now on, see [`ReportBuilder::set_os`]
+    pub fn set_os(&mut self, os: Option<HostOs>) {
+        self.report.set_os(os);
+    }
+
+    /// Report for the keys currently held
+    pub fn report(&self) -> KeyboardReport {
+        self.report.report()
+    }
+
+    /// Keys currently held, to type text on top of, see
+    /// [`UsbKeyboard::type_text`](crate::usb::UsbKeyboard::type_text)
+    pub fn held(&self) -> &ReportBuilder {
+        &self.report
+    }
+
+    /// Layers switched on by `TG` keys, one bit per layer
+    pub fn toggled_layers(&self) -> u32 {
+        self.toggled
+    }
+
+    /// One-shot layers locked by a double tap
+    pub fn locked_layers(&self) -> u32 {
+        self.locked_layers
+    }
+
+    /// Switches on the toggled and locked layers saved before a reboot,
+    /// ignoring layers the keymap does not have
+    pub fn restore_layers(&mut self, toggled: u32, locked: u32) {
+        let existing = u32::MAX
+            .checked_shr(u32::BITS - N_LAYERS.min(32) as u32)
+            .unwrap_or(0);
+        self.toggled = toggled & existing;
+        self.locked_layers = locked & existing;
+        info!("Active layers: {:#b}", self.active_layers());
+    }
+
+    /// Active layers as a bit mask, the base layer is always active
+    pub fn active_layers(&self) -> u32 {
+        let armed = self.armed_layer.map_or(0, |layer| 1 << layer);
+        1 | self.momentary | self.toggled | self.oneshot_layers | self.locked_layers | armed
+    }
+
+    /// Highest active layer with a key at the position that is not
+    /// transparent
+    fn resolve(&self, row: usize, col: usize) -> KeyCode {
+        let active = self.active_layers();
+        (0..N_LAYERS)
+            .rev()
+            .filter(|&layer| active & (1 << layer) != 0)
+            .map(|layer| self.layers[layer][row][col])
+            .find(|&keycode| keycode != KeyCode::Extra(Extra::Transparent))
+            .unwrap_or(KeyCode::Extra(Extra::NA))
+    }
+
+    fn layer_action(&mut self, action: LayerAction, pressed: bool) {
+        let (LayerAction::Momentary(layer) | LayerAction::Toggle(layer)) = action;
+        if usize::from(layer) >= N_LAYERS {
+            warn!("No layer {}", layer);
+            return;
+        }
+        let bit = 1 << layer;
+        match action {
+            LayerAction::Momentary(_) if pressed => self.momentary |= bit,
+            LayerAction::Momentary(_) => self.momentary &= !bit,
+            LayerAction::Toggle(_) if pressed => self.toggled ^= bit,
+            LayerAction::Toggle(_) => {}
+        }
+        info!("Active layers: {:#b}", self.active_layers());
+    }
+
+    /// Drops one-shot modifiers and layer not used within
+    /// [`ONESHOT_TIMEOUT`]
+    fn expire_oneshot(&mut self, at: Instant) {
+        if at.saturating_duration_since(self.armed_at) > ONESHOT_TIMEOUT {
+            self.armed_mods = Mods::NONE;
+            self.armed_layer = None;
+        }
+    }
+
+    fn oneshot(&mut self, oneshot: OneShot, pressed: bool, at: Instant) -> Option<Effect> {
+        if let OneShot::Layer(layer) = oneshot
+            && usize::from(layer) >= N_LAYERS
+        {
+            warn!("No layer {}", layer);
+            return None;
+        }
+
+        if pressed {
+            if self.oneshot_held == 0 {
+                self.interrupted = false;
+            }
+            self.oneshot_held += 1;
+            return match oneshot {
+                OneShot::Mods(mods) => {
+                    self.report.hold_mods(mods);
+                    Some(Effect::Report)
+                }
+                OneShot::Layer(layer) => {
+                    self.oneshot_layers |= 1 << layer;
+                    None
+                }
+            };
+        }
+
+        self.oneshot_held = self.oneshot_held.saturating_sub(1);
+        self.expire_oneshot(at);
+        // Held while another key was pressed, the key acted like a plain
+        // modifier or layer key
+        let tapped = !self.interrupted;
+        match oneshot {
+            OneShot::Mods(mods) => {
+                self.report.release_mods(mods);
+                if tapped {
+                    self.tap_mods(mods, at);
+                }
+                Some(Effect::Report)
+            }
+            OneShot::Layer(layer) => {
+                self.oneshot_layers &= !(1 << layer);
+                if tapped {
+                    self.tap_layer(layer, at);
+                }
+                None
+            }
+        }
+    }
+
+    /// Whether a tap at `at` follows the one that armed the one-shot keys
+    /// within [`ONESHOT_TAPPING_TERM`]
+    fn double_tapped(&self, at: Instant) -> bool {
+        at.saturating_duration_since(self.armed_at) <= ONESHOT_TAPPING_TERM
+    }
+
+    /// Arms one-shot modifiers, locks them on a quick second tap and unlocks
+    /// them on a third
+    fn tap_mods(&mut self, mods: Mods, at: Instant) {
+        if self.locked_mods.contains(mods) {
+            self.locked_mods = self.locked_mods.difference(mods);
+            self.report.release_mods(mods);
+        } else if self.armed_mods.contains(mods) && self.double_tapped(at) {
+            info!("One-shot {:?} locked", mods);
+            self.armed_mods = self.armed_mods.difference(mods);
+            self.locked_mods = self.locked_mods.union(mods);
+            self.report.hold_mods(mods);
+        } else {
+            self.armed_mods = self.armed_mods.union(mods);
+            self.armed_at = at;
+        }
+    }
+
+    /// [`tap_mods`](Self::tap_mods) for a one-shot layer
+    fn tap_layer(&mut self, layer: u8, at: Instant) {
+        let bit = 1 << layer;
+        if self.locked_layers & bit != 0 {
+            self.locked_layers &= !bit;
+        } else if self.armed_layer == Some(layer) && self.double_tapped(at) {
+            info!("One-shot layer {} locked", layer);
+            self.armed_layer = None;
+            self.locked_layers |= bit;
+        } else {
+            self.armed_layer = Some(layer);
+            self.armed_at = at;
+        }
+    }
+
+    /// Processes a switch event that happened at `at`
+    pub fn process(&mut self, event: KeyEvent, at: Instant) -> Option<Effect> {
+        if event.row >= N_ROWS || event.col >= N_COLS {
+            warn!("No key at ({}, {})", event.row, event.col);
+            return None;
+        }
+
+        let keycode = if event.pressed {
+            self.expire_oneshot(at);
+            let keycode = self.resolve(event.row, event.col);
+            // One-shot keys are released as looked up, other keys as
+            // modified below
+            self.pressed_as[event.row][event.col] = keycode;
+            keycode
+        } else {
+            self.pressed_as[event.row][event.col]
+        };
+
+        // Modifier and layer keys leave one-shot keys waiting for the key
+        // they are meant for
+        let uses_oneshot = match keycode {
+            KeyCode::OneShot(oneshot) => return self.oneshot(oneshot, event.pressed, at),
+            KeyCode::Layer(_) | KeyCode::Macos(_) => false,
+            KeyCode::Base(_) | KeyCode::Modified(..) => keycode.to_hid_values().1 != 0,
+            _ => true,
+        };
+        let keycode = if event.pressed && uses_oneshot {
+            self.interrupted = true;
+            self.armed_layer = None;
+            let mods = core::mem::replace(&mut self.armed_mods, Mods::NONE);
+            match keycode {
+                KeyCode::Base(_) | KeyCode::Modified(..) if !mods.is_empty() => {
+                    keycode.with_mods(mods)
+                }
+                _ => keycode,
+            }
+        } else {
+            keycode
+        };
+        let keycode = if event.pressed {
+            let held = Mods::from_bits(self.report.modifier());
+            let keycode = self.caps_word.apply(keycode, held, self.caps_lock, at);
+            self.pressed_as[event.row][event.col] = keycode;
+            keycode
+        } else {
+            keycode
+        };
+
+        match keycode {
+            KeyCode::Extra(Extra::NA | Extra::Transparent) => None,
+            KeyCode::Extra(Extra::CapsWord) => {
+                if event.pressed {
+                    self.caps_word.toggle(at);
+                    info!("Caps Word: {}", self.caps_word.is_active(at));
+                }
+                None
+            }
+            KeyCode::Extra(extra) => event.pressed.then_some(Effect::Action(extra)),
+            KeyCode::Layer(action) => {
+                self.layer_action(action, event.pressed);
+                None
+            }
+            _ if event.pressed => {
+                info!(
+                    "Key pressed at ({}, {}): {:?}",
+                    event.row, event.col, keycode
+                );
+                if !self.report.press(keycode) {
+                    warn!("Too many keys held, dropping {:?}", keycode);
+                    self.diagnostics.record_rollover();
+                }
+                Some(Effect::Report)
+            }
+            _ => {
+                self.report.release(keycode);
+                Some(Effect::Report)
+            }
+        }
+    }
+}
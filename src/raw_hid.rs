@@ -0,0 +1,205 @@
+//! Vendor-defined raw HID interface for host tools.
+//!
+//! The host writes a 32-byte output report whose first byte is a
+//! [`Command`] and the keyboard answers with a 32-byte input report starting
+//! with the same byte. Unknown commands are answered with [`UNKNOWN`] in the
+//! second byte.
+//!
+//! In diagnostic mode the keyboard also sends reports on its own, starting
+//! with [`SWITCH_EVENT`].
+
+use defmt::Format;
+
+use crate::{
+    battery::BatteryState,
+    board::Hand,
+    diagnostics::{Diagnostics, MAX_STUCK},
+    matrix::KeyEvent,
+    physical::PhysicalKey,
+};
+
+/// Size of the input and output reports
+pub const REPORT_LEN: usize = 32;
+
+/// Status byte answering a command the firmware does not know
+pub const UNKNOWN: u8 = 0xFF;
+
+/// First byte of the reports streamed in diagnostic mode, see
+/// [`switch_report`]
+pub const SWITCH_EVENT: u8 = 0x80;
+
+/// Report descriptor on the vendor usage page 0xFF00. The protocol is this
+/// firmware's own, so it stays off 0xFF60, where VIA and Vial would send it
+/// their commands.
+#[rustfmt::skip]
+pub const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,       // Usage (0x01)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x02,       //   Usage (0x02)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20,       //   Report Count (32)
+    0x75, 0x08,       //   Report Size (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x09, 0x03,       //   Usage (0x03)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20,       //   Report Count (32)
+    0x75, 0x08,       //   Report Size (8)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute)
+    0xC0,             // End Collection
+];
+
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+#[repr(u8)]
+pub enum Command {
+    /// Answers with the charge in percent followed by the battery voltage in
+    /// millivolts as little-endian `u16` of this half, all zero before the
+    /// first reading
+    Battery = 0x01,
+    /// Answers with the diagnostics counters as little-endian `u32`: times
+    /// the key queue was full, key presses lost to rollover, then keys that
+    /// completed a ghost rectangle
+    Diagnostics = 0x02,
+    /// Enters diagnostic mode if the second byte is 1 or leaves it if it is
+    /// 0, then answers with whether the mode is on, the number of stuck
+    /// switches, the bit masks of shorted rows and columns as little-endian
+    /// `u16` and up to [`MAX_STUCK`] stuck (row, column) pairs, as found when
+    /// the board started. Pairs of stuck switches that are not listed read
+    /// 0xFF.
+    DiagnosticMode = 0x03,
+    /// Answers with the number of physical keys on this half, then for the
+    /// key whose index is in the second byte its matrix row and column and
+    /// its x, y and rotation as little-endian `f32`, see
+    /// [`PhysicalKey`]. The key fields are zero for an index past the end.
+    PhysicalKey = 0x04,
+    /// Stores the hand in the second byte, 0 for left and 1 for right, for
+    /// boards using
+    /// [`HandDetection::Stored`](crate::board::HandDetection::Stored), taking
+    /// effect at the next boot. Answers with the stored hand, or [`UNKNOWN`]
+    /// for another value.
+    StoreHand = 0x05,
+}
+
+impl Command {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(Command::Battery),
+            0x02 => Some(Command::Diagnostics),
+            0x03 => Some(Command::DiagnosticMode),
+            0x04 => Some(Command::PhysicalKey),
+            0x05 => Some(Command::StoreHand),
+            _ => None,
+        }
+    }
+}
+
+/// Answers raw HID requests from the state shared by the firmware tasks
+pub struct RawHidHandler<'a> {
+    battery: &'a BatteryState,
+    diagnostics: &'a Diagnostics,
+    keys: &'a [PhysicalKey],
+}
+
+impl<'a> RawHidHandler<'a> {
+    pub fn new(
+        battery: &'a BatteryState,
+        diagnostics: &'a Diagnostics,
+        keys: &'a [PhysicalKey],
+    ) -> Self {
+        Self {
+            battery,
+            diagnostics,
+            keys,
+        }
+    }
+
+    pub fn handle(&self, request: &[u8]) -> [u8; REPORT_LEN] {
+        let mut response = [0; REPORT_LEN];
+        let Some(&byte) = request.first() else {
+            response[1] = UNKNOWN;
+            return response;
+        };
+        response[0] = byte;
+
+        match Command::from_byte(byte) {
+            Some(Command::Battery) => {
+                write_battery(&mut response[1..4], self.battery);
+            }
+            Some(Command::Diagnostics) => {
+                let counters = self.diagnostics.counters();
+                response[1..5].copy_from_slice(&counters.queue_full.to_le_bytes());
+                response[5..9].copy_from_slice(&counters.rollover.to_le_bytes());
+                response[9..13].copy_from_slice(&counters.ghosts.to_le_bytes());
+            }
+            Some(Command::DiagnosticMode) => {
+                match request.get(1) {
+                    Some(0) => self.diagnostics.set_diagnostic_mode(false),
+                    Some(1) => self.diagnostics.set_diagnostic_mode(true),
+                    _ => {}
+                }
+                let self_test = self.diagnostics.self_test();
+                response[1] = self.diagnostics.diagnostic_mode() as u8;
+                response[2] = self_test.stuck_count();
+                response[3..5].copy_from_slice(&self_test.shorted_rows.to_le_bytes());
+                response[5..7].copy_from_slice(&self_test.shorted_cols.to_le_bytes());
+                let pairs = &mut response[7..7 + 2 * MAX_STUCK];
+                // Marks the stuck switches that are counted but not listed
+                pairs.fill(u8::MAX);
+                for (out, (row, col)) in pairs.chunks_exact_mut(2).zip(self_test.stuck()) {
+                    out.copy_from_slice(&[*row, *col]);
+                }
+            }
+            Some(Command::PhysicalKey) => {
+                response[1] = self.keys.len() as u8;
+                let index = request.get(1).copied().unwrap_or_default();
+                if let Some(key) = self.keys.get(usize::from(index)) {
+                    response[2] = key.row as u8;
+                    response[3] = key.col as u8;
+                    response[4..8].copy_from_slice(&key.x.to_le_bytes());
+                    response[8..12].copy_from_slice(&key.y.to_le_bytes());
+                    response[12..16].copy_from_slice(&key.rotation.to_le_bytes());
+                }
+            }
+            Some(Command::StoreHand) => {
+                response[1] = match hand_to_store(request) {
+                    Some(Hand::Left) => 0,
+                    Some(Hand::Right) => 1,
+                    None => UNKNOWN,
+                };
+            }
+            None => response[1] = UNKNOWN,
+        }
+        response
+    }
+}
+
+/// Hand a [`Command::StoreHand`] request asks to store, for the firmware to
+/// persist
+pub fn hand_to_store(request: &[u8]) -> Option<Hand> {
+    match request {
+        [command, 0, ..] if *command == Command::StoreHand as u8 => Some(Hand::Left),
+        [command, 1, ..] if *command == Command::StoreHand as u8 => Some(Hand::Right),
+        _ => None,
+    }
+}
+
+/// Report streamed in diagnostic mode for a switch changing state: the row,
+/// the column and 1 for a press or 0 for a release
+pub fn switch_report(event: KeyEvent) -> [u8; REPORT_LEN] {
+    let mut report = [0; REPORT_LEN];
+    report[..4].copy_from_slice(&[
+        SWITCH_EVENT,
+        event.row as u8,
+        event.col as u8,
+        event.pressed as u8,
+    ]);
+    report
+}
+
+fn write_battery(out: &mut [u8], battery: &BatteryState) {
+    out[0] = battery.percentage().unwrap_or_default();
+    let millivolts = battery.millivolts().unwrap_or_default();
+    out[1..3].copy_from_slice(&millivolts.to_le_bytes());
+}
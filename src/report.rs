@@ -0,0 +1,170 @@
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::{
+    keycodes::{KeyCode, MacosKeys, Mods},
+    os::{self, HostOs},
+};
+
+/// Number of non-modifier keys a boot keyboard report can hold
+pub const ROLLOVER: usize = 6;
+
+/// Bit of the report's second byte carrying the Apple Fn key. Boot keyboards
+/// reserve that byte, the report descriptor declares it as Apple's vendor
+/// usage, see [`crate::usb::KEYBOARD_REPORT_DESCRIPTOR`].
+pub const APPLE_FN: u8 = 1 << 0;
+
+/// Keyboard report built from the keys currently held, so every report
+/// reflects the full matrix state rather than a single key event
+#[derive(Copy, Debug, Clone, Default, Eq, PartialEq)]
+pub struct ReportBuilder {
+    /// Number of held keys holding each modifier bit, so releasing one of two
+    /// keys with the same modifier keeps it held
+    modifiers: [u8; 8],
+    /// Modifiers added by the last pressed [`KeyCode::Modified`] key, applied
+    /// until it is released or another key is pressed
+    weak_modifier: u8,
+    weak_usage: u8,
+    /// Number of held Apple Fn keys
+    apple_fn: u8,
+    keycodes: [u8; ROLLOVER],
+    /// OS keys are translated for, see [`os::translate`]
+    os: Option<HostOs>,
+}
+
+impl ReportBuilder {
+    pub const fn new() -> Self {
+        Self {
+            modifiers: [0; 8],
+            weak_modifier: 0,
+            weak_usage: 0,
+            apple_fn: 0,
+            keycodes: [0; ROLLOVER],
+            os: None,
+        }
+    }
+
+    /// Translates keys for `os` from now on. Held keys are dropped when the
+    /// OS changes, since they were pressed with the old translation.
+    pub fn set_os(&mut self, os: Option<HostOs>) {
+        if os != self.os {
+            *self = Self { os, ..Self::new() };
+        }
+    }
+
+    /// Adds a held key, returning `false` if all key slots are taken and the
+    /// key cannot be reported
+    pub fn press(&mut self, keycode: KeyCode) -> bool {
+        if keycode == KeyCode::Macos(MacosKeys::Fn) {
+            self.apple_fn = self.apple_fn.saturating_add(1);
+            return true;
+        }
+
+        let keycode = os::translate(keycode, self.os);
+        let (modifier, usage) = keycode.to_hid_values();
+        if usage == 0 {
+            self.hold_modifiers(modifier);
+            return true;
+        }
+
+        if !self.keycodes.contains(&usage) {
+            let Some(slot) = self.keycodes.iter_mut().find(|slot| **slot == 0) else {
+                return false;
+            };
+            *slot = usage;
+        }
+
+        // A key pressed after a modified one is typed without its modifiers,
+        // so rolling from `(` to `a` gives `a`
+        (self.weak_modifier, self.weak_usage) = match keycode {
+            KeyCode::Modified(..) => (modifier, usage),
+            _ => (0, 0),
+        };
+        true
+    }
+
+    /// Removes a released key, keeping the remaining keys in press order
+    pub fn release(&mut self, keycode: KeyCode) {
+        if keycode == KeyCode::Macos(MacosKeys::Fn) {
+            self.apple_fn = self.apple_fn.saturating_sub(1);
+            return;
+        }
+
+        let (modifier, usage) = os::translate(keycode, self.os).to_hid_values();
+        if usage == 0 {
+            self.release_modifiers(modifier);
+            return;
+        }
+
+        if usage == self.weak_usage {
+            self.weak_modifier = 0;
+            self.weak_usage = 0;
+        }
+        if let Some(index) = self.keycodes.iter().position(|slot| *slot == usage) {
+            self.keycodes.copy_within(index + 1.., index);
+            self.keycodes[ROLLOVER - 1] = 0;
+        }
+    }
+
+    /// Holds modifiers without a key, like a modifier key would
+    pub fn hold_mods(&mut self, mods: Mods) {
+        self.hold_modifiers(os::translate_mods(mods, self.os).bits());
+    }
+
+    /// Releases modifiers held with [`hold_mods`](Self::hold_mods)
+    pub fn release_mods(&mut self, mods: Mods) {
+        self.release_modifiers(os::translate_mods(mods, self.os).bits());
+    }
+
+    fn hold_modifiers(&mut self, modifier: u8) {
+        for (bit, holds) in self.modifiers.iter_mut().enumerate() {
+            if modifier & (1 << bit) != 0 {
+                *holds = holds.saturating_add(1);
+            }
+        }
+    }
+
+    fn release_modifiers(&mut self, modifier: u8) {
+        for (bit, holds) in self.modifiers.iter_mut().enumerate() {
+            if modifier & (1 << bit) != 0 {
+                *holds = holds.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Modifier byte of the report: the modifiers held plus those of the
+    /// modified key being typed
+    pub fn modifier(&self) -> u8 {
+        let held = self
+            .modifiers
+            .iter()
+            .enumerate()
+            .filter(|(_, holds)| **holds > 0)
+            .fold(0, |modifier, (bit, _)| modifier | 1 << bit);
+        held | self.weak_modifier
+    }
+
+    pub fn report(&self) -> KeyboardReport {
+        KeyboardReport {
+            modifier: self.modifier(),
+            reserved: if self.apple_fn > 0 { APPLE_FN } else { 0 },
+            leds: 0,
+            keycodes: self.keycodes,
+        }
+    }
+
+    /// Report typing `usage` with exactly `mods` while the held keys stay
+    /// down, or releasing it again for a `usage` of 0. The held modifiers are
+    /// left out so typed text does not turn into shortcuts. With every key
+    /// slot taken the key is not added.
+    pub fn typing(&self, mods: Mods, usage: u8) -> KeyboardReport {
+        let mut report = self.report();
+        report.modifier = os::translate_mods(mods, self.os).bits();
+        if usage != 0
+            && !report.keycodes.contains(&usage)
+            && let Some(slot) = report.keycodes.iter_mut().find(|slot| **slot == 0)
+        {
+            *slot = usage;
+        }
+        report
+    }
+}
@@ -0,0 +1,79 @@
+use defmt::Format;
+
+use crate::{board::Hand, os::OsMode};
+
+/// Size of the serialized settings record in bytes
+pub const SETTINGS_LEN: usize = 16;
+
+const MAGIC: [u8; 4] = *b"DCTY";
+const UNSET: u8 = 0xFF;
+
+/// Settings persisted across reboots. Waking from System OFF is a reboot
+/// too, so any state that should survive deep sleep belongs here.
+///
+/// Every field occupies its own bytes in the record and erased bytes
+/// (`0xFF`) mean "not set", so fields can be appended without invalidating
+/// records written by older firmware.
+#[derive(Copy, Debug, Clone, Default, Eq, PartialEq, Format)]
+pub struct Settings {
+    pub hand: Option<Hand>,
+    pub os_mode: OsMode,
+    /// Layers switched on by `TG` keys when the board entered System OFF, one
+    /// bit per layer
+    pub toggled_layers: u32,
+    /// One-shot layers locked when the board entered System OFF
+    pub locked_layers: u32,
+}
+
+impl Settings {
+    pub fn to_bytes(&self) -> [u8; SETTINGS_LEN] {
+        let mut bytes = [UNSET; SETTINGS_LEN];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = match self.hand {
+            Some(Hand::Left) => 0,
+            Some(Hand::Right) => 1,
+            None => UNSET,
+        };
+        bytes[5] = match self.os_mode {
+            OsMode::Off => UNSET,
+            OsMode::MacOs => 2,
+            OsMode::Auto => 3,
+        };
+        bytes[6..10].copy_from_slice(&self.toggled_layers.to_le_bytes());
+        bytes[10..14].copy_from_slice(&self.locked_layers.to_le_bytes());
+        bytes
+    }
+
+    /// Parses a settings record, returning `None` if it was never written
+    pub fn from_bytes(bytes: &[u8; SETTINGS_LEN]) -> Option<Self> {
+        if bytes[..4] != MAGIC {
+            return None;
+        }
+
+        let hand = match bytes[4] {
+            0 => Some(Hand::Left),
+            1 => Some(Hand::Right),
+            _ => None,
+        };
+        let os_mode = match bytes[5] {
+            2 => OsMode::MacOs,
+            3 => OsMode::Auto,
+            _ => OsMode::Off,
+        };
+
+        Some(Self {
+            hand,
+            os_mode,
+            toggled_layers: layer_mask(&bytes[6..10]),
+            locked_layers: layer_mask(&bytes[10..14]),
+        })
+    }
+}
+
+/// Reads a little-endian layer mask, erased bytes meaning no layer
+fn layer_mask(bytes: &[u8]) -> u32 {
+    match u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) {
+        u32::MAX => 0,
+        mask => mask,
+    }
+}
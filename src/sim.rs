@@ -0,0 +1,223 @@
+//! Host-side keyboard simulator.
+//!
+//! Replays a script of switch events through the same matrix, layout and
+//! keyboard code the firmware runs, with simulated GPIO in place of the
+//! nRF52840 pins, and prints every HID report that would be
+//! sent to the host. The script is read from the file given as the first
+//! argument, or from stdin:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! press 1 1     # press the switch at row 1, column 1
+//! release 1 1
+//! tap 2 3       # press and release
+//! wait 50       # let 50 ms pass
+//! ```
+//!
+//! Pass `--right` to use the right half's layout.
+
+use std::{
+    env, fs,
+    io::{self, Read},
+    process,
+    sync::atomic::AtomicBool,
+};
+
+use dactyl_rs::{
+    board::Hand,
+    diagnostics::Diagnostics,
+    layout::{CAPS_WORD, get_layout},
+    matrix::{ActiveLevel, DiodeDirection, Ghosting, Matrix, MatrixConfig},
+    processor::{Effect, KeyProcessor},
+    usb::{ReportWriter, UsbKeyboard},
+};
+use embassy_executor::Spawner;
+use embassy_time::{Delay, Duration, Instant, Timer};
+use embassy_usb::driver::EndpointError;
+use usbd_hid::descriptor::KeyboardReport;
+
+static CONFIGURED: AtomicBool = AtomicBool::new(true);
+static DIAGNOSTICS: Diagnostics = Diagnostics::new();
+
+/// Simulated matrix wiring, the same the matrix tests use. The columns are
+/// the outputs and the rows the inputs. The tests use more of it.
+#[allow(dead_code)]
+#[path = "../tests/common/gpio.rs"]
+mod gpio;
+
+use gpio::{MockGpio, MockInput, MockOutput};
+
+type SimMatrix<'a, const N_COLS: usize, const N_ROWS: usize> =
+    Matrix<MockOutput<'a, N_COLS, N_ROWS>, MockInput<'a, N_COLS, N_ROWS>, Delay, N_COLS, N_ROWS>;
+
+/// Opens or closes the switch at (`row`, `col`) for the script
+fn set_switch<const N_COLS: usize, const N_ROWS: usize>(
+    gpio: &MockGpio<N_COLS, N_ROWS>,
+    row: usize,
+    col: usize,
+    pressed: bool,
+) -> Result<(), String> {
+    if row >= N_ROWS || col >= N_COLS {
+        return Err(format!("no switch at ({row}, {col})"));
+    }
+    gpio.set(col, row, pressed);
+    Ok(())
+}
+
+/// Fake HID sink printing every report
+struct PrintWriter {
+    start: Instant,
+}
+
+impl ReportWriter for PrintWriter {
+    async fn write_report(&mut self, report: &KeyboardReport) -> Result<(), EndpointError> {
+        println!(
+            "{:>8} ms  modifier={:#04x} keycodes={:02x?}",
+            self.start.elapsed().as_millis(),
+            report.modifier,
+            report.keycodes
+        );
+        Ok(())
+    }
+}
+
+enum Command {
+    Press(usize, usize),
+    Release(usize, usize),
+    Tap(usize, usize),
+    Wait(u64),
+}
+
+fn parse_line(line: &str) -> Result<Option<Command>, String> {
+    let line = line.split('#').next().unwrap_or_default();
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return Ok(None);
+    };
+
+    let mut number = |name: &str| -> Result<u64, String> {
+        words
+            .next()
+            .ok_or_else(|| format!("missing {name}"))?
+            .parse()
+            .map_err(|_| format!("invalid {name}"))
+    };
+
+    let command = match command {
+        "press" => Command::Press(number("row")? as usize, number("column")? as usize),
+        "release" => Command::Release(number("row")? as usize, number("column")? as usize),
+        "tap" => Command::Tap(number("row")? as usize, number("column")? as usize),
+        "wait" => Command::Wait(number("duration")?),
+        other => return Err(format!("unknown command `{other}`")),
+    };
+    Ok(Some(command))
+}
+
+/// Scans the matrix and sends a report for every key event
+async fn scan<const N_COLS: usize, const N_ROWS: usize, const N_LAYERS: usize>(
+    matrix: &mut SimMatrix<'_, N_COLS, N_ROWS>,
+    processor: &mut KeyProcessor<'_, N_COLS, N_ROWS, N_LAYERS>,
+    keyboard: &mut UsbKeyboard<'_, PrintWriter>,
+) {
+    let result = matrix
+        .scan_keys(
+            async |event| match processor.process(event, Instant::now()) {
+                Some(Effect::Report) => {
+                    if let Err(e) = keyboard.send_report(&processor.report()).await {
+                        eprintln!("Failed to send report: {e:?}");
+                    }
+                }
+                Some(Effect::Action(action)) => println!("action {action:?}"),
+                None => {}
+            },
+        )
+        .await;
+    if let Err(e) = result {
+        eprintln!("Matrix scan failed: {e:?}");
+    }
+}
+
+fn read_script(path: Option<&str>) -> io::Result<String> {
+    match path {
+        Some(path) => fs::read_to_string(path),
+        None => {
+            let mut script = String::new();
+            io::stdin().read_to_string(&mut script)?;
+            Ok(script)
+        }
+    }
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut hand = Hand::Left;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--left" => hand = Hand::Left,
+            "--right" => hand = Hand::Right,
+            _ => path = Some(arg),
+        }
+    }
+
+    let script = read_script(path.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Failed to read script: {e}");
+        process::exit(1);
+    });
+
+    let mut processor = KeyProcessor::new(get_layout(hand), &DIAGNOSTICS).with_caps_word(CAPS_WORD);
+    let gpio = MockGpio::new(ActiveLevel::High);
+    let mut matrix = Matrix::new(
+        gpio.outputs(),
+        gpio.inputs(),
+        Delay,
+        MatrixConfig {
+            diode_direction: DiodeDirection::Col2Row,
+            active_level: ActiveLevel::High,
+            ghosting: Ghosting::Trust,
+            scan_interval: Duration::from_millis(10),
+            // Never waits while idle: the script runs on the scanning task,
+            // so it could not close the switch that would wake the matrix
+            idle_timeout: None,
+        },
+    );
+    let mut keyboard = UsbKeyboard::new(
+        PrintWriter {
+            start: Instant::now(),
+        },
+        &CONFIGURED,
+    );
+
+    for (number, line) in script.lines().enumerate() {
+        let command = parse_line(line).unwrap_or_else(|e| {
+            eprintln!("line {}: {e}", number + 1);
+            process::exit(1);
+        });
+
+        let result = match command {
+            None => continue,
+            Some(Command::Wait(ms)) => {
+                Timer::after_millis(ms).await;
+                Ok(())
+            }
+            Some(Command::Press(row, col)) => set_switch(&gpio, row, col, true),
+            Some(Command::Release(row, col)) => set_switch(&gpio, row, col, false),
+            Some(Command::Tap(row, col)) => match set_switch(&gpio, row, col, true) {
+                Ok(()) => {
+                    scan(&mut matrix, &mut processor, &mut keyboard).await;
+                    set_switch(&gpio, row, col, false)
+                }
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            eprintln!("line {}: {e}", number + 1);
+            process::exit(1);
+        }
+
+        scan(&mut matrix, &mut processor, &mut keyboard).await;
+    }
+
+    // The executor never returns on its own
+    process::exit(0);
+}
@@ -0,0 +1,54 @@
+use defmt::{info, warn};
+use embassy_nrf::nvmc::{Error, Nvmc, PAGE_SIZE};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::settings::{SETTINGS_LEN, Settings};
+
+unsafe extern "C" {
+    // Defined in memory.x
+    static __storage_start: u8;
+}
+
+/// Persistent settings kept in the first page of the `STORAGE` flash region
+pub struct Storage<'d> {
+    flash: Nvmc<'d>,
+    offset: u32,
+}
+
+impl<'d> Storage<'d> {
+    pub fn new(flash: Nvmc<'d>) -> Self {
+        let offset = &raw const __storage_start as u32;
+        Self { flash, offset }
+    }
+
+    /// Reads the stored settings, falling back to defaults if the page is
+    /// erased or unreadable
+    pub fn load(&mut self) -> Settings {
+        let mut bytes = [0; SETTINGS_LEN];
+        if let Err(e) = self.flash.read(self.offset, &mut bytes) {
+            warn!("Failed to read settings: {:?}", e);
+            return Settings::default();
+        }
+
+        Settings::from_bytes(&bytes).unwrap_or_default()
+    }
+
+    /// Changes the stored settings with `change`, writing them only if they
+    /// differ to spare the flash
+    pub fn update(&mut self, change: impl FnOnce(&mut Settings)) -> Result<(), Error> {
+        let stored = self.load();
+        let mut settings = stored;
+        change(&mut settings);
+        if settings == stored {
+            return Ok(());
+        }
+        self.save(&settings)
+    }
+
+    pub fn save(&mut self, settings: &Settings) -> Result<(), Error> {
+        info!("Saving settings: {:?}", settings);
+        self.flash
+            .erase(self.offset, self.offset + PAGE_SIZE as u32)?;
+        self.flash.write(self.offset, &settings.to_bytes())
+    }
+}
@@ -0,0 +1,73 @@
+/// Fixed-capacity ASCII string for text built at runtime without an
+/// allocator. Pushes past the capacity are dropped.
+#[derive(Copy, Debug, Clone, Eq, PartialEq)]
+pub struct TextBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> TextBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Appends an ASCII byte
+    pub fn push(&mut self, byte: u8) {
+        if byte.is_ascii()
+            && let Some(slot) = self.bytes.get_mut(self.len)
+        {
+            *slot = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Appends the ASCII characters of `text`
+    pub fn push_str(&mut self, text: &str) {
+        for byte in text.bytes() {
+            self.push(byte);
+        }
+    }
+
+    /// Appends `value` in decimal
+    pub fn push_decimal(&mut self, value: u32) {
+        let mut digits = [0; 10];
+        let mut count = 0;
+        let mut value = value;
+        loop {
+            digits[count] = b'0' + (value % 10) as u8;
+            count += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        for digit in digits[..count].iter().rev() {
+            self.push(*digit);
+        }
+    }
+
+    /// Appends `value` as upper-case hex, `digits` nibbles wide
+    pub fn push_hex(&mut self, value: u64, digits: u32) {
+        for nibble in (0..digits).rev() {
+            let nibble = (value >> (nibble * 4)) as u8 & 0xF;
+            self.push(match nibble {
+                0..=9 => b'0' + nibble,
+                _ => b'A' + nibble - 10,
+            });
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ASCII is ever pushed
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+impl<const N: usize> Default for TextBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,68 +1,236 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 
-use defmt::{info, warn};
-use embassy_time;
+use defmt::{Format, info, warn};
+use embassy_time::{Instant, Timer};
 use embassy_usb::{
     Handler,
-    class::hid::{HidWriter, ReportId, RequestHandler},
+    class::hid::{self, HidWriter, ReportId, RequestHandler},
     control::OutResponse,
+    driver::{Driver, EndpointError},
 };
 use usbd_hid::descriptor::KeyboardReport;
 
-use crate::keycodes::KeyCode;
+use crate::{
+    board::UsbIdentity,
+    fingerprint::HostFingerprint,
+    identity::DEVICE_RELEASE,
+    keycodes::{Mods, ascii_to_hid},
+    report::ReportBuilder,
+};
+
+/// Size of a keyboard report on the wire
+pub const KEYBOARD_REPORT_LEN: usize = 8;
+
+/// Boot keyboard report descriptor with the Apple Fn key in the reserved
+/// byte, the way Apple keyboards report it: usage 0x03 (keyboard Fn) on the
+/// vendor page 0xFF (top case). The report stays 8 bytes in the boot layout,
+/// so BIOSes and other hosts ignore the bit.
+///
+/// The bit is only honoured by hosts that accept the vendor usage from this
+/// keyboard. macOS may ignore it from keyboards that are not Apple's, then
+/// the key does nothing.
+#[rustfmt::skip]
+pub const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x06,       // Usage (Keyboard)
+    0xA1, 0x01,       // Collection (Application)
+    // Modifier byte
+    0x05, 0x07,       //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,       //   Usage Minimum (Left Control)
+    0x29, 0xE7,       //   Usage Maximum (Right GUI)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x08,       //   Report Count (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    // Apple Fn in bit 0 of the reserved byte
+    0x05, 0xFF,       //   Usage Page (Apple Vendor Top Case)
+    0x09, 0x03,       //   Usage (Keyboard Fn)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x95, 0x07,       //   Report Count (7)
+    0x81, 0x03,       //   Input (Constant, Variable, Absolute)
+    // LEDs
+    0x05, 0x08,       //   Usage Page (LEDs)
+    0x19, 0x01,       //   Usage Minimum (Num Lock)
+    0x29, 0x05,       //   Usage Maximum (Kana)
+    0x95, 0x05,       //   Report Count (5)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute)
+    0x75, 0x03,       //   Report Size (3)
+    0x95, 0x01,       //   Report Count (1)
+    0x91, 0x03,       //   Output (Constant, Variable, Absolute)
+    // Key array
+    0x05, 0x07,       //   Usage Page (Keyboard/Keypad)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x29, 0xDD,       //   Usage Maximum (Keypad Hexadecimal)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xDD, 0x00, //   Logical Maximum (221)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x06,       //   Report Count (6)
+    0x81, 0x00,       //   Input (Data, Array, Absolute)
+    0xC0,             // End Collection
+];
+
+/// Device configuration for a keyboard with the given identity
+pub fn device_config<'a>(
+    identity: &UsbIdentity,
+    product: &'a str,
+    serial_number: &'a str,
+) -> embassy_usb::Config<'a> {
+    let mut config = embassy_usb::Config::new(identity.vid, identity.pid);
+    config.manufacturer = Some(identity.manufacturer);
+    config.product = Some(product);
+    config.serial_number = Some(serial_number);
+    config.device_release = DEVICE_RELEASE;
+    config.max_power = identity.max_power;
+    config.max_packet_size_0 = 64;
+    config.supports_remote_wakeup = true;
+    config
+}
+
+/// HID class configuration of the boot keyboard interface. Hosts set the
+/// LEDs either through the interrupt OUT endpoint or with a control request,
+/// which goes to `request_handler`.
+pub fn keyboard_hid_config<'a>(
+    poll_ms: u8,
+    request_handler: Option<&'a mut dyn RequestHandler>,
+) -> hid::Config<'a> {
+    hid::Config {
+        report_descriptor: KEYBOARD_REPORT_DESCRIPTOR,
+        request_handler,
+        poll_ms,
+        max_packet_size: 64,
+    }
+}
+
+/// Destination for keyboard reports, implemented by the HID endpoint writer
+/// and by fake sinks when running off the hardware
+#[allow(async_fn_in_trait)]
+pub trait ReportWriter {
+    async fn write_report(&mut self, report: &KeyboardReport) -> Result<(), EndpointError>;
+}
 
-pub struct UsbKeyboard<'d, D: embassy_usb::driver::Driver<'d>, const N: usize> {
-    writer: HidWriter<'d, D, N>,
+impl<'d, D: Driver<'d>, const N: usize> ReportWriter for HidWriter<'d, D, N> {
+    async fn write_report(&mut self, report: &KeyboardReport) -> Result<(), EndpointError> {
+        self.write_serialize(report).await
+    }
+}
+
+/// Why a report could not be sent
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Format)]
+pub enum HidError {
+    /// The host has not configured the device (yet)
+    NotConfigured,
+    /// The endpoint is disabled, e.g. the cable was unplugged or the bus was
+    /// reset while writing
+    Disconnected,
+    /// The endpoint rejected the report
+    Endpoint(EndpointError),
+}
+
+impl From<EndpointError> for HidError {
+    fn from(e: EndpointError) -> Self {
+        match e {
+            EndpointError::Disabled => HidError::Disconnected,
+            e => HidError::Endpoint(e),
+        }
+    }
+}
+
+pub struct UsbKeyboard<'d, W: ReportWriter> {
+    writer: W,
     configured: &'d AtomicBool,
 }
 
-impl<'d, D: embassy_usb::driver::Driver<'d>, const N: usize> UsbKeyboard<'d, D, N> {
-    pub fn new(writer: HidWriter<'d, D, N>, configured: &'d AtomicBool) -> Self {
+impl<'d, W: ReportWriter> UsbKeyboard<'d, W> {
+    pub fn new(writer: W, configured: &'d AtomicBool) -> Self {
         Self { writer, configured }
     }
 
-    pub async fn send_key_report(&mut self, keycode: KeyCode) {
+    /// Sends a report, returning when it was written
+    pub async fn send_report(&mut self, report: &KeyboardReport) -> Result<Instant, HidError> {
         // Check if USB device is configured before sending reports
         if !self.configured.load(Ordering::Relaxed) {
-            warn!("USB device not configured, skipping key report");
-            return;
+            return Err(HidError::NotConfigured);
+        }
+
+        self.writer.write_report(report).await?;
+        Ok(Instant::now())
+    }
+
+    /// Types `text` one character at a time on top of the keys in `held`,
+    /// skipping characters that have no key, then sends `held` again. With
+    /// `caps_lock` on, letters are typed with the opposite Shift state so
+    /// their case comes out as written. Stops at the first report that
+    /// cannot be sent.
+    pub async fn type_text(
+        &mut self,
+        text: &str,
+        held: &ReportBuilder,
+        caps_lock: bool,
+    ) -> Result<(), HidError> {
+        for c in text.chars() {
+            let Some((modifier, keycode)) = ascii_to_hid(c) else {
+                warn!("No key for character {}", c);
+                continue;
+            };
+            let mut mods = Mods::from_bits(modifier);
+            if caps_lock && c.is_ascii_alphabetic() {
+                mods = Mods::from_bits(modifier ^ Mods::LSFT.bits());
+            }
+
+            self.send_report(&held.typing(mods, keycode)).await?;
+            // Give the host time to register the press before the release
+            Timer::after_millis(10).await;
+            self.send_report(&held.typing(Mods::NONE, 0)).await?;
         }
+        self.send_report(&held.report()).await?;
+        Ok(())
+    }
+}
 
-        let (modifier_byte, normal_key) = keycode.to_hid_values();
+/// Caps Lock bit of the LED byte in the keyboard's output report
+pub const CAPS_LOCK_LED: u8 = 1 << 1;
 
-        let report = KeyboardReport {
-            keycodes: [normal_key, 0, 0, 0, 0, 0],
-            leds: 0,
-            modifier: modifier_byte,
-            reserved: 0,
-        };
+/// Lock LEDs the host last set through the keyboard's output report, shared
+/// between the USB stack and the keyboard task
+pub struct HostLeds(AtomicU8);
 
-        match self.writer.write_serialize(&report).await {
-            Ok(()) => {}
-            Err(e) => warn!("Failed to send report: {:?}", e),
-        };
+impl HostLeds {
+    pub const fn new() -> Self {
+        Self(AtomicU8::new(0))
+    }
+
+    pub fn set(&self, leds: u8) {
+        self.0.store(leds, Ordering::Relaxed);
+    }
 
-        // Send a key release report after the key press
-        // This ensures the key doesn't get "stuck"
-        embassy_time::Timer::after_millis(10).await;
+    pub fn leds(&self) -> u8 {
+        self.0.load(Ordering::Relaxed)
+    }
 
-        let release_report = KeyboardReport {
-            keycodes: [0, 0, 0, 0, 0, 0],
-            leds: 0,
-            modifier: 0,
-            reserved: 0,
-        };
+    pub fn caps_lock(&self) -> bool {
+        self.leds() & CAPS_LOCK_LED != 0
+    }
+}
 
-        match self.writer.write_serialize(&release_report).await {
-            Ok(()) => {}
-            Err(e) => warn!("Failed to send release report: {:?}", e),
-        };
+impl Default for HostLeds {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub struct UsbRequestHandler {}
+pub struct UsbRequestHandler<'d> {
+    leds: &'d HostLeds,
+}
+
+impl<'d> UsbRequestHandler<'d> {
+    pub fn new(leds: &'d HostLeds) -> Self {
+        Self { leds }
+    }
+}
 
-impl RequestHandler for UsbRequestHandler {
+impl RequestHandler for UsbRequestHandler<'_> {
     fn get_report(&mut self, id: ReportId, _buf: &mut [u8]) -> Option<usize> {
         info!("Get report for {:?}", id);
         None
@@ -70,6 +238,11 @@ impl RequestHandler for UsbRequestHandler {
 
     fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
         info!("Set report for {:?}: {=[u8]}", id, data);
+        if let ReportId::Out(_) = id
+            && let Some(&leds) = data.first()
+        {
+            self.leds.set(leds);
+        }
         OutResponse::Accepted
     }
 
@@ -86,13 +259,19 @@ impl RequestHandler for UsbRequestHandler {
 pub struct UsbHandler<'d> {
     configured: &'d AtomicBool,
     suspended: &'d AtomicBool,
+    fingerprint: &'d HostFingerprint,
 }
 
 impl<'d> UsbHandler<'d> {
-    pub fn new(configured: &'d AtomicBool, suspended: &'d AtomicBool) -> Self {
+    pub fn new(
+        configured: &'d AtomicBool,
+        suspended: &'d AtomicBool,
+        fingerprint: &'d HostFingerprint,
+    ) -> Self {
         Self {
             configured,
             suspended,
+            fingerprint,
         }
     }
 
@@ -104,6 +283,7 @@ impl<'d> UsbHandler<'d> {
 impl<'d> Handler for UsbHandler<'d> {
     fn enabled(&mut self, enabled: bool) {
         self.configured.store(false, Ordering::Relaxed);
+        self.fingerprint.clear();
         self.suspended.store(false, Ordering::Release);
         if enabled {
             info!("Device enabled");
@@ -113,7 +293,11 @@ impl<'d> Handler for UsbHandler<'d> {
     }
 
     fn reset(&mut self) {
-        self.configured.store(false, Ordering::Relaxed);
+        // Hosts reset the bus during enumeration too, only a reset of a
+        // configured device starts a new one
+        if self.configured.swap(false, Ordering::Relaxed) {
+            self.fingerprint.clear();
+        }
         info!("Bus reset, the Vbus current limit is 100mA");
     }
 
@@ -125,6 +309,7 @@ impl<'d> Handler for UsbHandler<'d> {
     fn configured(&mut self, configured: bool) {
         self.configured.store(configured, Ordering::Relaxed);
         if configured {
+            self.fingerprint.set_configured();
             info!(
                 "Device configured, it may now draw up to the configured current limit from Vbus."
             )
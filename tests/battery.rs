@@ -0,0 +1,83 @@
+//! Battery voltage conversion, the discharge curve and smoothing.
+
+use dactyl_rs::battery::{BatteryConfig, BatteryFilter, BatteryInput, LevelText, percentage};
+use dactyl_rs::board::Hand;
+
+#[test]
+fn converts_samples_through_the_divider() {
+    let vddh = BatteryConfig {
+        input: BatteryInput::VddhDiv5,
+        divider: (5, 1),
+    };
+    // Half of the 3.6 V full scale, times 5
+    assert_eq!(vddh.millivolts(2048), 9000);
+    assert_eq!(vddh.millivolts(-3), 0);
+
+    let external = BatteryConfig {
+        input: BatteryInput::Ain(2),
+        divider: (3, 2),
+    };
+    assert_eq!(external.millivolts(2048), 2700);
+}
+
+#[test]
+fn curve_endpoints() {
+    assert_eq!(percentage(4200), 100);
+    assert_eq!(percentage(3500), 0);
+}
+
+#[test]
+fn interpolates_between_curve_points() {
+    // Halfway between 4150 mV (95%) and 4200 mV (100%), rounded down
+    assert_eq!(percentage(4175), 97);
+    // On a point
+    assert_eq!(percentage(3910), 50);
+    // Halfway between 3500 mV (0%) and 3800 mV (15%)
+    assert_eq!(percentage(3650), 7);
+}
+
+#[test]
+fn clamps_outside_the_curve() {
+    assert_eq!(percentage(4350), 100);
+    assert_eq!(percentage(u16::MAX), 100);
+    assert_eq!(percentage(3200), 0);
+    assert_eq!(percentage(0), 0);
+}
+
+#[test]
+fn falls_with_the_voltage() {
+    let levels: Vec<u8> = (3400..=4300).step_by(5).map(percentage).collect();
+    assert!(levels.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+#[test]
+fn filter_starts_at_the_first_reading() {
+    let mut filter = BatteryFilter::new();
+    assert_eq!(filter.update(3900), 3900);
+}
+
+#[test]
+fn filter_smooths_a_spike() {
+    let mut filter = BatteryFilter::new();
+    filter.update(4000);
+    assert_eq!(filter.update(3600), 3900);
+    assert_eq!(filter.update(4000), 3925);
+}
+
+#[test]
+fn filter_converges_on_small_changes_both_ways() {
+    let mut filter = BatteryFilter::new();
+    filter.update(3900);
+    let rising = (0..40).map(|_| filter.update(3902)).last();
+    assert_eq!(rising, Some(3902));
+
+    let falling = (0..40).map(|_| filter.update(3899)).last();
+    assert_eq!(falling, Some(3899));
+}
+
+#[test]
+fn level_text_names_the_half() {
+    assert_eq!(LevelText::new(Hand::Left, Some(87)).as_str(), "L 87%");
+    assert_eq!(LevelText::new(Hand::Right, Some(100)).as_str(), "R 100%");
+    assert_eq!(LevelText::new(Hand::Right, None).as_str(), "R ?");
+}
@@ -0,0 +1,138 @@
+//! Caps Word: shifted letters, continue keys, word ends and Caps Lock.
+
+mod common;
+
+use common::Board;
+use dactyl_rs::{
+    caps_word::CapsWordConfig,
+    diagnostics::Diagnostics,
+    keycodes::{KeyboardUsage, Mods},
+    keymap,
+    layout::Layers,
+    physical::{Keymap, PhysicalKey, PhysicalLayout, matrix_layers},
+    processor::KeyProcessor,
+};
+use embassy_time::Duration;
+
+const A: u8 = KeyboardUsage::KeyboardAa as u8;
+const MINUS: u8 = KeyboardUsage::KeyboardDashUnderscore as u8;
+const ONE: u8 = KeyboardUsage::Keyboard1Exclamation as u8;
+
+const KEYS: PhysicalLayout<6> = [
+    PhysicalKey::new(0, 0, 0.0, 0.0),
+    PhysicalKey::new(0, 1, 1.0, 0.0),
+    PhysicalKey::new(0, 2, 2.0, 0.0),
+    PhysicalKey::new(0, 3, 3.0, 0.0),
+    PhysicalKey::new(0, 4, 4.0, 0.0),
+    PhysicalKey::new(0, 5, 5.0, 0.0),
+];
+
+const KEYMAP: [Keymap<6>; 1] = keymap! {
+    [ CW_TOGG A MINS 1 SPC LCTL ]
+};
+
+const LAYERS: Layers<6, 1, 1> = matrix_layers(&KEYS, &KEYMAP);
+
+const TOGGLE: usize = 0;
+const KEY_A: usize = 1;
+const KEY_MINUS: usize = 2;
+const KEY_1: usize = 3;
+const SPACE: usize = 4;
+const CTRL: usize = 5;
+
+#[test]
+fn shifts_letters_and_minus_until_space() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(TOGGLE);
+    assert!(board.processor.caps_word(board.now));
+    assert_eq!(board.tap(KEY_A), (Mods::LSFT.bits(), vec![A]));
+    assert_eq!(board.tap(KEY_MINUS), (Mods::LSFT.bits(), vec![MINUS]));
+    // Digits continue the word without a shift
+    assert_eq!(board.tap(KEY_1), (0, vec![ONE]));
+    assert_eq!(board.tap(KEY_A), (Mods::LSFT.bits(), vec![A]));
+
+    board.tap(SPACE);
+    assert!(!board.processor.caps_word(board.now));
+    assert_eq!(board.tap(KEY_A), (0, vec![A]));
+}
+
+#[test]
+fn shift_is_released_with_the_key() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(TOGGLE);
+    board.switch(KEY_A, true);
+    board.switch(KEY_A, false);
+    assert_eq!(board.processor.report().modifier, 0);
+    assert!(board.processor.caps_word(board.now));
+}
+
+#[test]
+fn second_tap_turns_it_off() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(TOGGLE);
+    board.tap(TOGGLE);
+    assert!(!board.processor.caps_word(board.now));
+    assert_eq!(board.tap(KEY_A), (0, vec![A]));
+}
+
+#[test]
+fn ends_after_timeout() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(TOGGLE);
+    board.tap(KEY_A);
+    board.now += CapsWordConfig::DEFAULT.timeout;
+    assert!(!board.processor.caps_word(board.now));
+    assert_eq!(board.tap(KEY_A), (0, vec![A]));
+}
+
+#[test]
+fn shortcuts_end_the_word() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(TOGGLE);
+    board.switch(CTRL, true);
+    // Holding Ctrl alone keeps the word going
+    assert!(board.processor.caps_word(board.now));
+    assert_eq!(board.tap(KEY_A), (Mods::LCTL.bits(), vec![A]));
+    board.switch(CTRL, false);
+    assert!(!board.processor.caps_word(board.now));
+}
+
+#[test]
+fn leaves_letters_alone_with_caps_lock_on() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.processor.set_caps_lock(true);
+    board.tap(TOGGLE);
+    assert_eq!(board.tap(KEY_A), (0, vec![A]));
+    assert_eq!(board.tap(KEY_MINUS), (Mods::LSFT.bits(), vec![MINUS]));
+    assert!(board.processor.caps_word(board.now));
+}
+
+#[test]
+fn follows_configured_keys() {
+    const CONFIG: CapsWordConfig = CapsWordConfig {
+        timeout: Duration::from_secs(1),
+        shifted: &[],
+        continue_keys: &[KeyboardUsage::KeyboardDashUnderscore],
+    };
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+    board.processor = KeyProcessor::new(LAYERS, &diagnostics).with_caps_word(CONFIG);
+
+    board.tap(TOGGLE);
+    assert_eq!(board.tap(KEY_MINUS), (0, vec![MINUS]));
+    assert!(board.processor.caps_word(board.now));
+    board.tap(KEY_1);
+    assert!(!board.processor.caps_word(board.now));
+}
@@ -0,0 +1,159 @@
+//! Simulated matrix wiring for the [`Matrix`](dactyl_rs::matrix::Matrix)
+//! scanner, shared by the matrix tests and the host-side simulator.
+//!
+//! A [`MockGpio`] holds the switches between every output and input line.
+//! The matrix drives the [`MockOutput`] pins and reads the [`MockInput`]
+//! pins back, which see the active level through any closed switch on a
+//! driven output and the pull to the inactive level otherwise. [`NoDelay`]
+//! lets tests scan without waiting, the settle time means nothing here.
+
+use std::{cell::RefCell, convert::Infallible};
+
+use dactyl_rs::matrix::ActiveLevel;
+use embassy_futures::yield_now;
+use embedded_hal::digital::{ErrorType, InputPin, OutputPin};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+pub struct MockGpio<const N_OUT: usize, const N_IN: usize> {
+    active_level: ActiveLevel,
+    switches: RefCell<[[bool; N_IN]; N_OUT]>,
+    /// Inputs tied to the active level, by a short to the supply or ground
+    shorted: RefCell<[bool; N_IN]>,
+    /// Level of each output, `true` for high
+    outputs: RefCell<[bool; N_OUT]>,
+}
+
+impl<const N_OUT: usize, const N_IN: usize> MockGpio<N_OUT, N_IN> {
+    /// Wiring with every switch open and the outputs at the inactive level
+    pub fn new(active_level: ActiveLevel) -> Self {
+        Self {
+            active_level,
+            switches: RefCell::new([[false; N_IN]; N_OUT]),
+            shorted: RefCell::new([false; N_IN]),
+            outputs: RefCell::new([active_level == ActiveLevel::Low; N_OUT]),
+        }
+    }
+
+    /// Opens or closes the switch between `output` and `input`
+    pub fn set(&self, output: usize, input: usize, closed: bool) {
+        self.switches.borrow_mut()[output][input] = closed;
+    }
+
+    /// Shorts `input` to the active level, so it reads active even when no
+    /// output is driven
+    pub fn short(&self, input: usize, shorted: bool) {
+        self.shorted.borrow_mut()[input] = shorted;
+    }
+
+    /// Whether `output` is driven at the active level
+    pub fn driven(&self, output: usize) -> bool {
+        self.outputs.borrow()[output] == (self.active_level == ActiveLevel::High)
+    }
+
+    pub fn output(&self, output: usize) -> MockOutput<'_, N_OUT, N_IN> {
+        MockOutput { gpio: self, output }
+    }
+
+    pub fn input(&self, input: usize) -> MockInput<'_, N_OUT, N_IN> {
+        MockInput { gpio: self, input }
+    }
+
+    pub fn outputs(&self) -> [MockOutput<'_, N_OUT, N_IN>; N_OUT] {
+        core::array::from_fn(|output| self.output(output))
+    }
+
+    pub fn inputs(&self) -> [MockInput<'_, N_OUT, N_IN>; N_IN] {
+        core::array::from_fn(|input| self.input(input))
+    }
+
+    fn input_is_high(&self, input: usize) -> bool {
+        let switches = self.switches.borrow();
+        let active = self.shorted.borrow()[input]
+            || (0..N_OUT).any(|output| self.driven(output) && switches[output][input]);
+        active == (self.active_level == ActiveLevel::High)
+    }
+}
+
+pub struct MockOutput<'a, const N_OUT: usize, const N_IN: usize> {
+    gpio: &'a MockGpio<N_OUT, N_IN>,
+    output: usize,
+}
+
+impl<const N_OUT: usize, const N_IN: usize> ErrorType for MockOutput<'_, N_OUT, N_IN> {
+    type Error = Infallible;
+}
+
+impl<const N_OUT: usize, const N_IN: usize> OutputPin for MockOutput<'_, N_OUT, N_IN> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.gpio.outputs.borrow_mut()[self.output] = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.gpio.outputs.borrow_mut()[self.output] = true;
+        Ok(())
+    }
+}
+
+pub struct MockInput<'a, const N_OUT: usize, const N_IN: usize> {
+    gpio: &'a MockGpio<N_OUT, N_IN>,
+    input: usize,
+}
+
+impl<const N_OUT: usize, const N_IN: usize> ErrorType for MockInput<'_, N_OUT, N_IN> {
+    type Error = Infallible;
+}
+
+impl<const N_OUT: usize, const N_IN: usize> InputPin for MockInput<'_, N_OUT, N_IN> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.gpio.input_is_high(self.input))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+// Switches are set by other code on the same thread, so polling is enough
+// here
+impl<const N_OUT: usize, const N_IN: usize> Wait for MockInput<'_, N_OUT, N_IN> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        while !self.is_high()? {
+            yield_now().await;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        while !self.is_low()? {
+            yield_now().await;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_low().await?;
+        self.wait_for_high().await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_high().await?;
+        self.wait_for_low().await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        if self.is_high()? {
+            self.wait_for_low().await
+        } else {
+            self.wait_for_high().await
+        }
+    }
+}
+
+/// Delay returning right away, for scanning outside an executor with a time
+/// driver
+pub struct NoDelay;
+
+impl DelayNs for NoDelay {
+    async fn delay_ns(&mut self, _ns: u32) {}
+}
@@ -0,0 +1,399 @@
+//! Fixtures shared by the host-side tests.
+//!
+//! [`Wire`] is an in-memory USB bus standing in for the cable: the device
+//! side is an [`embassy_usb::driver::Driver`] handed to the same builder the
+//! firmware uses, the host side is [`Host`], which sends setup packets, reads
+//! back the control responses and collects everything written to IN
+//! endpoints.
+//!
+//! [`Board`] drives a key processor over a one-row matrix with a clock the
+//! test advances by hand, and [`report_state`] reads a report back.
+//! [`gpio`] simulates the pins of a key matrix.
+
+// Every test binary uses only some of the fixtures
+#![allow(dead_code)]
+
+pub mod gpio;
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    future::pending,
+};
+
+use dactyl_rs::{
+    diagnostics::Diagnostics, layout::Layers, matrix::KeyEvent, processor::KeyProcessor,
+};
+use embassy_futures::yield_now;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant};
+use embassy_usb::driver::{
+    Bus, ControlPipe, Direction, Driver, Endpoint, EndpointAddress, EndpointAllocError,
+    EndpointError, EndpointIn, EndpointInfo, EndpointOut, EndpointType, Event, Unsupported,
+};
+use usbd_hid::descriptor::KeyboardReport;
+
+pub const GET_DESCRIPTOR: u8 = 0x06;
+pub const SET_ADDRESS: u8 = 0x05;
+pub const SET_CONFIGURATION: u8 = 0x09;
+pub const DEVICE_DESCRIPTOR: u8 = 0x01;
+pub const CONFIGURATION_DESCRIPTOR: u8 = 0x02;
+
+/// What the device answered to a control transfer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlStage {
+    DataIn { data: Vec<u8>, last: bool },
+    Accept,
+    Reject,
+    SetAddress(u8),
+}
+
+pub struct Wire {
+    setup: Channel<NoopRawMutex, [u8; 8], 1>,
+    control: Channel<NoopRawMutex, ControlStage, 8>,
+    events: Channel<NoopRawMutex, Event, 4>,
+    next_endpoint: Cell<usize>,
+    enabled: RefCell<HashSet<u8>>,
+    stalled: RefCell<HashSet<u8>>,
+    written: RefCell<Vec<Vec<u8>>>,
+}
+
+impl Wire {
+    pub fn new() -> Self {
+        Self {
+            setup: Channel::new(),
+            control: Channel::new(),
+            events: Channel::new(),
+            next_endpoint: Cell::new(1),
+            enabled: RefCell::default(),
+            stalled: RefCell::default(),
+            written: RefCell::default(),
+        }
+    }
+
+    pub fn driver(&self) -> MockDriver<'_> {
+        MockDriver { wire: self }
+    }
+
+    fn is_enabled(&self, addr: EndpointAddress) -> bool {
+        self.enabled.borrow().contains(&u8::from(addr))
+    }
+}
+
+pub struct MockDriver<'a> {
+    wire: &'a Wire,
+}
+
+impl<'a> MockDriver<'a> {
+    fn alloc(
+        &mut self,
+        direction: Direction,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<MockEndpoint<'a>, EndpointAllocError> {
+        let index = self.wire.next_endpoint.get();
+        if index > 15 {
+            return Err(EndpointAllocError);
+        }
+        self.wire.next_endpoint.set(index + 1);
+
+        Ok(MockEndpoint {
+            wire: self.wire,
+            info: EndpointInfo {
+                addr: EndpointAddress::from_parts(index, direction),
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+        })
+    }
+}
+
+impl<'a> Driver<'a> for MockDriver<'a> {
+    type EndpointOut = MockEndpoint<'a>;
+    type EndpointIn = MockEndpoint<'a>;
+    type ControlPipe = MockControlPipe<'a>;
+    type Bus = MockBus<'a>;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.alloc(Direction::Out, ep_type, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.alloc(Direction::In, ep_type, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        (
+            MockBus { wire: self.wire },
+            MockControlPipe {
+                wire: self.wire,
+                max_packet_size: control_max_packet_size as usize,
+            },
+        )
+    }
+}
+
+pub struct MockBus<'a> {
+    wire: &'a Wire,
+}
+
+impl Bus for MockBus<'_> {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        self.wire.events.receive().await
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        let mut endpoints = self.wire.enabled.borrow_mut();
+        if enabled {
+            endpoints.insert(ep_addr.into());
+        } else {
+            endpoints.remove(&ep_addr.into());
+        }
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut endpoints = self.wire.stalled.borrow_mut();
+        if stalled {
+            endpoints.insert(ep_addr.into());
+        } else {
+            endpoints.remove(&ep_addr.into());
+        }
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        self.wire.stalled.borrow().contains(&ep_addr.into())
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Ok(())
+    }
+}
+
+pub struct MockEndpoint<'a> {
+    wire: &'a Wire,
+    info: EndpointInfo,
+}
+
+impl Endpoint for MockEndpoint<'_> {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        while !self.wire.is_enabled(self.info.addr) {
+            yield_now().await;
+        }
+    }
+}
+
+impl EndpointIn for MockEndpoint<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        if !self.wire.is_enabled(self.info.addr) {
+            return Err(EndpointError::Disabled);
+        }
+        self.wire.written.borrow_mut().push(buf.to_vec());
+        Ok(())
+    }
+}
+
+impl EndpointOut for MockEndpoint<'_> {
+    // The host never sends output reports in these tests
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, EndpointError> {
+        pending().await
+    }
+}
+
+pub struct MockControlPipe<'a> {
+    wire: &'a Wire,
+    max_packet_size: usize,
+}
+
+impl ControlPipe for MockControlPipe<'_> {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        self.wire.setup.receive().await
+    }
+
+    // No test issues control writes with a data stage
+    async fn data_out(
+        &mut self,
+        _buf: &mut [u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<usize, EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+
+    async fn data_in(
+        &mut self,
+        data: &[u8],
+        _first: bool,
+        last: bool,
+    ) -> Result<(), EndpointError> {
+        let stage = ControlStage::DataIn {
+            data: data.to_vec(),
+            last,
+        };
+        self.wire.control.send(stage).await;
+        Ok(())
+    }
+
+    async fn accept(&mut self) {
+        self.wire.control.send(ControlStage::Accept).await;
+    }
+
+    async fn reject(&mut self) {
+        self.wire.control.send(ControlStage::Reject).await;
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        self.wire.control.send(ControlStage::SetAddress(addr)).await;
+    }
+}
+
+/// Host side of the [`Wire`]
+pub struct Host<'a> {
+    wire: &'a Wire,
+}
+
+impl<'a> Host<'a> {
+    pub fn new(wire: &'a Wire) -> Self {
+        Self { wire }
+    }
+
+    pub async fn bus_event(&self, event: Event) {
+        self.wire.events.send(event).await;
+    }
+
+    async fn setup(&self, request_type: u8, request: u8, value: u16, index: u16, length: u16) {
+        let mut packet = [request_type, request, 0, 0, 0, 0, 0, 0];
+        packet[2..4].copy_from_slice(&value.to_le_bytes());
+        packet[4..6].copy_from_slice(&index.to_le_bytes());
+        packet[6..8].copy_from_slice(&length.to_le_bytes());
+        self.wire.setup.send(packet).await;
+    }
+
+    /// Runs a standard device-to-host request, returning the data or `None`
+    /// if the device rejected it
+    pub async fn control_in(&self, request: u8, value: u16, length: u16) -> Option<Vec<u8>> {
+        self.setup(0x80, request, value, 0, length).await;
+
+        let mut data = Vec::new();
+        loop {
+            match self.wire.control.receive().await {
+                ControlStage::DataIn { data: chunk, last } => {
+                    data.extend_from_slice(&chunk);
+                    if last {
+                        return Some(data);
+                    }
+                }
+                ControlStage::Reject => return None,
+                stage => panic!("unexpected {stage:?} in an IN transfer"),
+            }
+        }
+    }
+
+    /// Runs a standard host-to-device request without data, returning how
+    /// the device answered
+    pub async fn control_out(&self, request: u8, value: u16) -> ControlStage {
+        self.setup(0x00, request, value, 0, 0).await;
+        self.wire.control.receive().await
+    }
+
+    pub async fn get_descriptor(&self, kind: u8, length: u16) -> Vec<u8> {
+        self.control_in(GET_DESCRIPTOR, u16::from(kind) << 8, length)
+            .await
+            .expect("descriptor request rejected")
+    }
+
+    /// Powers the bus and walks the device through enumeration up to
+    /// SET_CONFIGURATION
+    pub async fn enumerate(&self) {
+        self.bus_event(Event::PowerDetected).await;
+        self.bus_event(Event::Reset).await;
+
+        self.get_descriptor(DEVICE_DESCRIPTOR, 64).await;
+        assert_eq!(
+            self.control_out(SET_ADDRESS, 7).await,
+            ControlStage::SetAddress(7)
+        );
+        self.get_descriptor(CONFIGURATION_DESCRIPTOR, 255).await;
+        assert_eq!(
+            self.control_out(SET_CONFIGURATION, 1).await,
+            ControlStage::Accept
+        );
+    }
+
+    /// Takes every packet written to IN endpoints so far
+    pub fn take_written(&self) -> Vec<Vec<u8>> {
+        self.wire.written.take()
+    }
+}
+
+/// Modifier byte and held keycodes of a report
+pub fn report_state(report: &KeyboardReport) -> (u8, Vec<u8>) {
+    let keys = report
+        .keycodes
+        .into_iter()
+        .filter(|&code| code != 0)
+        .collect();
+    (report.modifier, keys)
+}
+
+/// Processor for a one-row matrix with a clock the test advances by hand.
+/// Every switch event takes 50 ms.
+pub struct Board<'a, const N_COLS: usize, const N_LAYERS: usize> {
+    pub processor: KeyProcessor<'a, N_COLS, 1, N_LAYERS>,
+    pub now: Instant,
+}
+
+impl<'a, const N_COLS: usize, const N_LAYERS: usize> Board<'a, N_COLS, N_LAYERS> {
+    pub fn new(layers: Layers<N_COLS, 1, N_LAYERS>, diagnostics: &'a Diagnostics) -> Self {
+        Self {
+            processor: KeyProcessor::new(layers, diagnostics),
+            now: Instant::from_secs(10),
+        }
+    }
+
+    pub fn switch(&mut self, col: usize, pressed: bool) {
+        let event = KeyEvent {
+            row: 0,
+            col,
+            pressed,
+        };
+        self.processor.process(event, self.now);
+        self.now += Duration::from_millis(50);
+    }
+
+    /// Taps a key, returning the [`state`](Self::state) while it was held
+    pub fn tap(&mut self, col: usize) -> (u8, Vec<u8>) {
+        self.switch(col, true);
+        let state = self.state();
+        self.switch(col, false);
+        state
+    }
+
+    /// Modifier byte and held keycodes
+    pub fn state(&self) -> (u8, Vec<u8>) {
+        report_state(&self.processor.report())
+    }
+}
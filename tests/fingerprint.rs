@@ -0,0 +1,75 @@
+//! Host OS guesses from the string descriptor requests of an enumeration.
+
+use dactyl_rs::{fingerprint::Fingerprint, os::HostOs};
+
+/// GET_DESCRIPTOR setup packet for string `index` with `length` bytes
+fn get_string(index: u8, length: u16) -> [u8; 8] {
+    let [length_low, length_high] = length.to_le_bytes();
+    [0x80, 0x06, index, 0x03, 0x09, 0x04, length_low, length_high]
+}
+
+/// GET_DESCRIPTOR setup packet for the device or configuration descriptor
+fn get_descriptor(kind: u8, length: u16) -> [u8; 8] {
+    let [length_low, length_high] = length.to_le_bytes();
+    [0x80, 0x06, 0, kind, 0, 0, length_low, length_high]
+}
+
+fn guess(setups: &[[u8; 8]]) -> Option<HostOs> {
+    let mut fingerprint = Fingerprint::new();
+    for setup in setups {
+        fingerprint.record(setup);
+    }
+    fingerprint.guess()
+}
+
+#[test]
+fn linux_reads_whole_strings() {
+    let setups = [
+        get_descriptor(0x01, 64),
+        get_descriptor(0x01, 18),
+        get_descriptor(0x02, 9),
+        get_descriptor(0x02, 59),
+        get_string(0, 255),
+        get_string(2, 255),
+        get_string(1, 255),
+        get_string(3, 255),
+    ];
+    assert_eq!(guess(&setups), Some(HostOs::Linux));
+}
+
+#[test]
+fn windows_reads_language_ids_with_4_bytes() {
+    let setups = [
+        get_descriptor(0x01, 64),
+        get_descriptor(0x01, 18),
+        get_string(3, 255),
+        get_string(0, 255),
+        get_string(2, 255),
+        get_descriptor(0x02, 255),
+        get_string(0, 4),
+        get_string(2, 14),
+    ];
+    assert_eq!(guess(&setups), Some(HostOs::Windows));
+}
+
+#[test]
+fn macos_reads_string_headers_first() {
+    let setups = [
+        get_descriptor(0x01, 8),
+        get_descriptor(0x01, 18),
+        get_descriptor(0x02, 9),
+        get_descriptor(0x02, 59),
+        get_string(0, 2),
+        get_string(0, 4),
+        get_string(2, 2),
+        get_string(2, 26),
+        get_string(1, 2),
+    ];
+    assert_eq!(guess(&setups), Some(HostOs::MacOs));
+}
+
+#[test]
+fn unknown_without_string_requests() {
+    let setups = [get_descriptor(0x01, 18), get_descriptor(0x02, 9), get_descriptor(0x02, 59)];
+    assert_eq!(guess(&setups), None);
+}
@@ -0,0 +1,159 @@
+//! Keymaps written with `keymap!` and how the processor resolves their
+//! layers.
+
+mod common;
+
+use common::report_state;
+use dactyl_rs::{
+    diagnostics::Diagnostics,
+    keycodes::{Extra, KeyCode, KeyboardUsage, LayerAction},
+    keymap,
+    layout::Layers,
+    matrix::KeyEvent,
+    physical::{Keymap, PhysicalKey, PhysicalLayout, matrix_layers, matrix_layout},
+    processor::{Effect, KeyProcessor},
+};
+use embassy_time::Instant;
+
+/// Three keys in a row of a 3x1 matrix, wired in reverse
+const KEYS: PhysicalLayout<3> = [
+    PhysicalKey::new(0, 2, 0.0, 0.0),
+    PhysicalKey::new(0, 1, 1.0, 0.0),
+    PhysicalKey::new(0, 0, 2.0, 0.0),
+];
+
+const KEYMAP: [Keymap<3>; 3] = keymap! {
+    [ A       MO(1)   TG(2)   ]
+    [ 1       _______ _______ ]
+    [ _______ XXXXXXX _______ ]
+};
+
+const LAYERS: Layers<3, 1, 3> = matrix_layers(&KEYS, &KEYMAP);
+
+fn event(col: usize, pressed: bool) -> KeyEvent {
+    KeyEvent {
+        row: 0,
+        col,
+        pressed,
+    }
+}
+
+#[test]
+fn names_expand_to_keycodes() {
+    assert_eq!(
+        keymap! { [ Q LSFT SCLN F12 RGHT BATT XXXXXXX _______ MO(3) TG(0) ] },
+        [[
+            KeyCode::Base(KeyboardUsage::KeyboardQq),
+            KeyCode::Base(KeyboardUsage::KeyboardLeftShift),
+            KeyCode::Base(KeyboardUsage::KeyboardSemiColon),
+            KeyCode::Base(KeyboardUsage::KeyboardF12),
+            KeyCode::Base(KeyboardUsage::KeyboardRightArrow),
+            KeyCode::Extra(Extra::BatteryLevel),
+            KeyCode::Extra(Extra::NA),
+            KeyCode::Extra(Extra::Transparent),
+            KeyCode::Layer(LayerAction::Momentary(3)),
+            KeyCode::Layer(LayerAction::Toggle(0)),
+        ]]
+    );
+}
+
+#[test]
+fn places_layers_by_physical_key() {
+    assert_eq!(LAYERS[0][0][2], dactyl_rs::kc!(A));
+    assert_eq!(LAYERS[0][0][0], KeyCode::Layer(LayerAction::Toggle(2)));
+    assert_eq!(LAYERS[1][0][2], dactyl_rs::kc!(1));
+}
+
+#[test]
+fn momentary_layer_while_held() {
+    let diagnostics = Diagnostics::new();
+    let mut processor = KeyProcessor::new(LAYERS, &diagnostics);
+
+    assert_eq!(processor.process(event(1, true), Instant::now()), None);
+    assert_eq!(processor.active_layers(), 0b11);
+    assert_eq!(
+        processor.process(event(2, true), Instant::now()),
+        Some(Effect::Report)
+    );
+    assert_eq!(report_state(&processor.report()), (0, vec![0x1e]));
+    processor.process(event(2, false), Instant::now());
+
+    processor.process(event(1, false), Instant::now());
+    assert_eq!(processor.active_layers(), 0b1);
+    processor.process(event(2, true), Instant::now());
+    assert_eq!(report_state(&processor.report()), (0, vec![0x04]));
+}
+
+#[test]
+fn toggled_layer_until_pressed_again() {
+    let diagnostics = Diagnostics::new();
+    let mut processor = KeyProcessor::new(LAYERS, &diagnostics);
+
+    processor.process(event(0, true), Instant::now());
+    processor.process(event(0, false), Instant::now());
+    assert_eq!(processor.active_layers(), 0b101);
+
+    // Transparent on layer 2, so the base layer's key
+    processor.process(event(2, true), Instant::now());
+    assert_eq!(report_state(&processor.report()), (0, vec![0x04]));
+    processor.process(event(2, false), Instant::now());
+
+    // The layer is toggled off through its own transparent key
+    processor.process(event(0, true), Instant::now());
+    processor.process(event(0, false), Instant::now());
+    assert_eq!(processor.active_layers(), 0b1);
+}
+
+#[test]
+fn releases_from_the_layer_pressed_on() {
+    let diagnostics = Diagnostics::new();
+    let mut processor = KeyProcessor::new(LAYERS, &diagnostics);
+
+    processor.process(event(1, true), Instant::now());
+    processor.process(event(2, true), Instant::now());
+    processor.process(event(1, false), Instant::now());
+    assert_eq!(report_state(&processor.report()), (0, vec![0x1e]));
+
+    assert_eq!(
+        processor.process(event(2, false), Instant::now()),
+        Some(Effect::Report)
+    );
+    assert_eq!(report_state(&processor.report()), (0, vec![]));
+}
+
+#[test]
+fn no_key_blocks_lower_layers() {
+    let diagnostics = Diagnostics::new();
+    let mut processor = KeyProcessor::new(LAYERS, &diagnostics);
+
+    processor.process(event(0, true), Instant::now());
+    processor.process(event(0, false), Instant::now());
+    // MO(1) is covered by XXXXXXX on layer 2
+    assert_eq!(processor.process(event(1, true), Instant::now()), None);
+    assert_eq!(processor.active_layers(), 0b101);
+}
+
+#[test]
+fn restores_saved_layers() {
+    let diagnostics = Diagnostics::new();
+    let mut processor = KeyProcessor::new(LAYERS, &diagnostics);
+
+    processor.process(event(0, true), Instant::now());
+    processor.process(event(0, false), Instant::now());
+    let toggled = processor.toggled_layers();
+    assert_eq!(toggled, 0b100);
+
+    let mut woken = KeyProcessor::new(LAYERS, &diagnostics);
+    // Layer 5 is not in the keymap
+    woken.restore_layers(toggled | 1 << 5, 0);
+    assert_eq!(woken.active_layers(), 0b101);
+    assert_eq!(woken.toggled_layers(), 0b100);
+}
+
+#[test]
+#[should_panic(expected = "two physical keys on the same switch")]
+fn rejects_a_switch_taken_by_a_no_key() {
+    let keys = [PhysicalKey::new(0, 0, 0.0, 0.0), PhysicalKey::new(0, 0, 1.0, 0.0)];
+    let keymap: Keymap<2> = keymap! { [ XXXXXXX A ] }[0];
+    matrix_layout::<1, 1, 2>(&keys, &keymap);
+}
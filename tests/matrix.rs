@@ -0,0 +1,355 @@
+//! The matrix scanner on mock pins, and ghost rectangle detection on
+//! synthetic matrix states, indexed by output and input line like the
+//! scanner's samples.
+
+mod common;
+
+use common::gpio::{MockGpio, MockInput, MockOutput, NoDelay};
+use dactyl_rs::diagnostics::{MAX_STUCK, SelfTest};
+use dactyl_rs::matrix::{
+    ActiveLevel, DiodeDirection, Ghosting, KeyEvent, Matrix, MatrixConfig, MatrixState, Sample,
+    completes_rectangle,
+};
+use embassy_futures::{block_on, join::join, yield_now};
+use embassy_time::Duration;
+use embedded_hal::digital::{ErrorType, InputPin};
+
+type MockMatrix<'a, const N_OUT: usize, const N_IN: usize> =
+    Matrix<MockOutput<'a, N_OUT, N_IN>, MockInput<'a, N_OUT, N_IN>, NoDelay, N_OUT, N_IN>;
+
+fn config(diode_direction: DiodeDirection, active_level: ActiveLevel) -> MatrixConfig {
+    MatrixConfig {
+        diode_direction,
+        active_level,
+        ghosting: Ghosting::Trust,
+        scan_interval: Duration::from_micros(100),
+        idle_timeout: None,
+    }
+}
+
+fn matrix<const N_OUT: usize, const N_IN: usize>(
+    gpio: &MockGpio<N_OUT, N_IN>,
+    config: MatrixConfig,
+) -> MockMatrix<'_, N_OUT, N_IN> {
+    Matrix::new(gpio.outputs(), gpio.inputs(), NoDelay, config)
+}
+
+/// Runs one scan, returning the events it reported
+fn scan<const N_OUT: usize, const N_IN: usize>(
+    matrix: &mut MockMatrix<'_, N_OUT, N_IN>,
+) -> Vec<KeyEvent> {
+    let mut events = Vec::new();
+    block_on(matrix.scan_keys(async |event| events.push(event))).unwrap();
+    events
+}
+
+fn event(row: usize, col: usize, pressed: bool) -> KeyEvent {
+    KeyEvent { row, col, pressed }
+}
+
+/// Builds a sample with the given (output, input) switches closed
+fn sample<const N_OUT: usize, const N_IN: usize>(closed: &[(usize, usize)]) -> Sample<N_OUT, N_IN> {
+    let mut sample = [[false; N_IN]; N_OUT];
+    for &(output, input) in closed {
+        sample[output][input] = true;
+    }
+    sample
+}
+
+/// The closed switches that changed state
+fn changed<const N_OUT: usize, const N_IN: usize>(
+    changed: &Sample<N_OUT, N_IN>,
+) -> Vec<(usize, usize)> {
+    (0..N_OUT)
+        .flat_map(|output| (0..N_IN).map(move |input| (output, input)))
+        .filter(|&(output, input)| changed[output][input])
+        .collect()
+}
+
+#[test]
+fn scans_presses_and_releases() {
+    // Columns are the outputs
+    let gpio = MockGpio::<3, 2>::new(ActiveLevel::High);
+    let mut matrix = matrix(&gpio, config(DiodeDirection::Col2Row, ActiveLevel::High));
+    assert!(scan(&mut matrix).is_empty());
+
+    gpio.set(2, 1, true);
+    assert_eq!(scan(&mut matrix), [event(1, 2, true)]);
+    // Held keys are only reported when they change
+    assert!(scan(&mut matrix).is_empty());
+
+    gpio.set(0, 0, true);
+    gpio.set(2, 1, false);
+    assert_eq!(scan(&mut matrix), [event(0, 0, true), event(1, 2, false)]);
+}
+
+#[test]
+fn scans_rows_as_outputs_for_row2col() {
+    // Rows are the outputs
+    let gpio = MockGpio::<2, 3>::new(ActiveLevel::High);
+    let mut matrix = matrix(&gpio, config(DiodeDirection::Row2Col, ActiveLevel::High));
+
+    gpio.set(1, 2, true);
+    assert_eq!(scan(&mut matrix), [event(1, 2, true)]);
+    gpio.set(1, 2, false);
+    assert_eq!(scan(&mut matrix), [event(1, 2, false)]);
+}
+
+#[test]
+fn scans_active_low() {
+    let gpio = MockGpio::<3, 2>::new(ActiveLevel::Low);
+    let mut matrix = matrix(&gpio, config(DiodeDirection::Col2Row, ActiveLevel::Low));
+    // The pull-ups read high with every switch open
+    assert!(scan(&mut matrix).is_empty());
+
+    gpio.set(0, 1, true);
+    gpio.set(2, 0, true);
+    assert_eq!(scan(&mut matrix), [event(1, 0, true), event(0, 2, true)]);
+    gpio.set(0, 1, false);
+    assert_eq!(scan(&mut matrix), [event(1, 0, false)]);
+    // Outputs are back at the inactive level between scans
+    assert!((0..3).all(|output| !gpio.driven(output)));
+}
+
+#[test]
+fn bounce_between_scans_goes_unseen() {
+    let gpio = MockGpio::<3, 2>::new(ActiveLevel::High);
+    let mut matrix = matrix(&gpio, config(DiodeDirection::Col2Row, ActiveLevel::High));
+
+    // Contact chatter settling before the next scan
+    for closed in [true, false, true, false] {
+        gpio.set(1, 1, closed);
+    }
+    assert!(scan(&mut matrix).is_empty());
+
+    gpio.set(1, 1, true);
+    assert_eq!(scan(&mut matrix), [event(1, 1, true)]);
+    for closed in [false, true] {
+        gpio.set(1, 1, closed);
+    }
+    assert!(scan(&mut matrix).is_empty());
+}
+
+/// Input that can only be polled, like a pin on an I/O expander without an
+/// interrupt line
+struct PollingInput<'a>(MockInput<'a, 3, 2>);
+
+impl ErrorType for PollingInput<'_> {
+    type Error = <MockInput<'static, 3, 2> as ErrorType>::Error;
+}
+
+impl InputPin for PollingInput<'_> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        self.0.is_high()
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        self.0.is_low()
+    }
+}
+
+#[test]
+fn scans_inputs_without_wait() {
+    let gpio = MockGpio::<3, 2>::new(ActiveLevel::High);
+    let mut matrix = Matrix::new(
+        gpio.outputs(),
+        gpio.inputs().map(PollingInput),
+        NoDelay,
+        config(DiodeDirection::Col2Row, ActiveLevel::High),
+    );
+
+    gpio.set(1, 0, true);
+    let mut events = Vec::new();
+    block_on(matrix.scan_keys(async |event| events.push(event))).unwrap();
+    assert_eq!(events, [event(0, 1, true)]);
+}
+
+#[test]
+fn stays_awake_before_the_idle_timeout() {
+    let gpio = MockGpio::<3, 2>::new(ActiveLevel::High);
+    let mut matrix = matrix(
+        &gpio,
+        MatrixConfig {
+            idle_timeout: Some(Duration::from_secs(60)),
+            ..config(DiodeDirection::Col2Row, ActiveLevel::High)
+        },
+    );
+
+    // Returns right away with no key pressed, so this does not hang
+    block_on(matrix.wait_while_idle()).unwrap();
+    assert!(scan(&mut matrix).is_empty());
+}
+
+#[test]
+fn goes_idle_and_wakes_on_a_press() {
+    let gpio = MockGpio::<3, 2>::new(ActiveLevel::High);
+    let mut matrix = matrix(
+        &gpio,
+        MatrixConfig {
+            idle_timeout: Some(Duration::from_ticks(0)),
+            ..config(DiodeDirection::Col2Row, ActiveLevel::High)
+        },
+    );
+    assert!(scan(&mut matrix).is_empty());
+
+    let (woken, ()) = block_on(join(matrix.wait_while_idle(), async {
+        for _ in 0..3 {
+            yield_now().await;
+        }
+        // Idle, with every output driven so any press shows on an input
+        assert!((0..3).all(|output| gpio.driven(output)));
+        gpio.set(2, 1, true);
+    }));
+    woken.unwrap();
+
+    assert!((0..3).all(|output| !gpio.driven(output)));
+    assert_eq!(scan(&mut matrix), [event(1, 2, true)]);
+}
+
+#[test]
+fn self_test_finds_stuck_keys() {
+    let gpio = MockGpio::<3, 2>::new(ActiveLevel::Low);
+    let mut matrix = matrix(&gpio, config(DiodeDirection::Col2Row, ActiveLevel::Low));
+    assert!(block_on(matrix.self_test()).unwrap().is_clean());
+
+    gpio.set(1, 0, true);
+    gpio.set(2, 1, true);
+    let report = block_on(matrix.self_test()).unwrap();
+    assert_eq!(report.stuck(), [(0, 1), (1, 2)]);
+    assert_eq!(report.stuck_count(), 2);
+    assert_eq!((report.shorted_rows, report.shorted_cols), (0, 0));
+}
+
+#[test]
+fn self_test_finds_shorted_lines() {
+    // Rows are the outputs
+    let gpio = MockGpio::<3, 2>::new(ActiveLevel::High);
+    let mut matrix = matrix(&gpio, config(DiodeDirection::Row2Col, ActiveLevel::High));
+
+    // Row 2 reads closed on every switch, column 1 reads active undriven
+    gpio.set(2, 0, true);
+    gpio.set(2, 1, true);
+    gpio.short(1, true);
+    // Not stuck, the short on column 1 hides it
+    gpio.set(0, 1, true);
+    let report = block_on(matrix.self_test()).unwrap();
+    assert_eq!(report.stuck(), []);
+    assert_eq!(report.shorted_rows, 1 << 2);
+    assert_eq!(report.shorted_cols, 1 << 1);
+}
+
+#[test]
+fn takes_stuck_keys_past_the_listed_ones() {
+    let mut report = SelfTest::new();
+    for row in 0..MAX_STUCK + 2 {
+        report.add_stuck(row, 0);
+    }
+
+    assert!(report.take_stuck(3, 0));
+    assert_eq!(report.stuck_count() as usize, MAX_STUCK + 1);
+    // The freed slot stays empty instead of repeating the last listed key
+    let listed: Vec<_> = (0..MAX_STUCK as u8)
+        .filter(|&row| row != 3)
+        .map(|row| (row, 0))
+        .collect();
+    assert_eq!(report.stuck(), listed);
+    // Unlisted keys are not known and cannot be taken
+    assert!(!report.take_stuck(MAX_STUCK, 0));
+}
+
+#[test]
+fn finds_rectangles() {
+    let rectangle = sample::<3, 4>(&[(0, 1), (0, 3), (2, 1), (2, 3)]);
+    for (output, input) in [(0, 1), (0, 3), (2, 1), (2, 3)] {
+        assert!(completes_rectangle(&rectangle, output, input));
+    }
+
+    // Keys sharing a line, or on different lines without a fourth corner,
+    // cannot ghost
+    let row = sample::<3, 4>(&[(1, 0), (1, 1), (1, 2), (1, 3)]);
+    assert!(!completes_rectangle(&row, 1, 2));
+    let column = sample::<3, 4>(&[(0, 2), (1, 2), (2, 2)]);
+    assert!(!completes_rectangle(&column, 1, 2));
+    let corner = sample::<3, 4>(&[(0, 0), (0, 3), (2, 3)]);
+    assert!(!completes_rectangle(&corner, 0, 3));
+}
+
+#[test]
+fn trust_takes_every_sample() {
+    let mut state = MatrixState::<2, 2>::new();
+    state.update_all(&sample(&[(0, 0), (0, 1), (1, 0)]), Ghosting::Trust);
+
+    let changes = state.update_all(&sample(&[(0, 0), (0, 1), (1, 0), (1, 1)]), Ghosting::Trust);
+    assert_eq!(changes.ghost, None);
+    assert_eq!(changed(&changes.changed), [(1, 1)]);
+    assert!(state.is_pressed(1, 1));
+}
+
+#[test]
+fn report_flags_the_closing_key() {
+    let mut state = MatrixState::<2, 2>::new();
+    let changes = state.update_all(&sample(&[(0, 0), (0, 1), (1, 0)]), Ghosting::Report);
+    assert_eq!(changes.ghost, None);
+
+    let changes = state.update_all(&sample(&[(0, 0), (0, 1), (1, 0), (1, 1)]), Ghosting::Report);
+    assert_eq!(changes.ghost, Some((1, 1)));
+    assert_eq!(changed(&changes.changed), [(1, 1)]);
+    assert!(state.is_pressed(1, 1));
+
+    // Only once, the key is pressed from now on
+    let changes = state.update_all(&sample(&[(0, 0), (0, 1), (1, 0), (1, 1)]), Ghosting::Report);
+    assert_eq!(changes.ghost, None);
+}
+
+#[test]
+fn suppress_holds_back_the_closing_key() {
+    let mut state = MatrixState::<2, 3>::new();
+    state.update_all(&sample(&[(0, 0), (0, 2), (1, 0)]), Ghosting::Suppress);
+
+    let ghosted = sample(&[(0, 0), (0, 2), (1, 0), (1, 2)]);
+    for _ in 0..3 {
+        let changes = state.update_all(&ghosted, Ghosting::Suppress);
+        assert_eq!(changes.ghost, Some((1, 2)));
+        assert!(changed(&changes.changed).is_empty());
+        assert!(!state.is_pressed(1, 2));
+    }
+
+    // Releasing a corner breaks the rectangle and lets the key through
+    let changes = state.update_all(&sample(&[(0, 0), (1, 0), (1, 2)]), Ghosting::Suppress);
+    assert_eq!(changes.ghost, None);
+    assert_eq!(changed(&changes.changed), [(0, 2), (1, 2)]);
+    assert!(!state.is_pressed(0, 2));
+    assert!(state.is_pressed(1, 2));
+}
+
+#[test]
+fn suppress_keeps_keys_held_before_the_rectangle() {
+    let mut state = MatrixState::<2, 2>::new();
+    state.update_all(&sample(&[(1, 1)]), Ghosting::Suppress);
+
+    // The rectangle closes at (0, 0) here, the key held since the first scan
+    // stays pressed
+    state.update_all(&sample(&[(0, 1), (1, 0), (1, 1)]), Ghosting::Suppress);
+    let changes = state.update_all(
+        &sample(&[(0, 0), (0, 1), (1, 0), (1, 1)]),
+        Ghosting::Suppress,
+    );
+    assert_eq!(changes.ghost, Some((0, 0)));
+    assert!(changed(&changes.changed).is_empty());
+    assert!(state.is_pressed(1, 1));
+    assert!(!state.is_pressed(0, 0));
+}
+
+#[test]
+fn suppress_blocks_a_rectangle_closed_in_one_scan() {
+    let mut state = MatrixState::<2, 2>::new();
+    let changes = state.update_all(
+        &sample(&[(0, 0), (0, 1), (1, 0), (1, 1)]),
+        Ghosting::Suppress,
+    );
+
+    // No way to tell which corner is the phantom
+    assert_eq!(changes.ghost, Some((0, 0)));
+    assert!(changed(&changes.changed).is_empty());
+    assert!(!state.any_pressed());
+}
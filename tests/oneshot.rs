@@ -0,0 +1,186 @@
+//! One-shot modifiers and layers: taps, holds, double taps and timeouts.
+
+mod common;
+
+use common::Board;
+use dactyl_rs::{
+    diagnostics::Diagnostics,
+    keycodes::{KeyCode, Mods, OneShot},
+    keymap,
+    layout::Layers,
+    matrix::KeyEvent,
+    os::HostOs,
+    physical::{Keymap, PhysicalKey, PhysicalLayout, matrix_layers},
+    processor::{KeyProcessor, ONESHOT_TAPPING_TERM, ONESHOT_TIMEOUT},
+};
+use embassy_time::Instant;
+
+const KEYS: PhysicalLayout<4> = [
+    PhysicalKey::new(0, 0, 0.0, 0.0),
+    PhysicalKey::new(0, 1, 1.0, 0.0),
+    PhysicalKey::new(0, 2, 2.0, 0.0),
+    PhysicalKey::new(0, 3, 3.0, 0.0),
+];
+
+const KEYMAP: [Keymap<4>; 2] = keymap! {
+    [ OSM(LSFT) OSL(1)  A       OSM(LCTL | LSFT) ]
+    [ _______   _______ 1       _______          ]
+};
+
+const LAYERS: Layers<4, 1, 2> = matrix_layers(&KEYS, &KEYMAP);
+
+#[test]
+fn tapped_modifier_applies_to_the_next_key_only() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(0);
+    assert_eq!(board.state(), (0, vec![]));
+
+    board.switch(2, true);
+    assert_eq!(board.state(), (Mods::LSFT.bits(), vec![0x04]));
+    board.switch(2, false);
+    assert_eq!(board.state(), (0, vec![]));
+
+    board.tap(2);
+    board.switch(2, true);
+    assert_eq!(board.state(), (0, vec![0x04]));
+}
+
+#[test]
+fn held_modifier_acts_like_a_plain_one() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.switch(0, true);
+    assert_eq!(board.state(), (Mods::LSFT.bits(), vec![]));
+    board.tap(2);
+    board.switch(0, false);
+    assert_eq!(board.state(), (0, vec![]));
+
+    // Nothing left waiting for the next key
+    board.switch(2, true);
+    assert_eq!(board.state(), (0, vec![0x04]));
+}
+
+#[test]
+fn double_tap_locks_until_tapped_again() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(3);
+    board.tap(3);
+    for _ in 0..2 {
+        board.tap(2);
+        assert_eq!(board.state(), ((Mods::LCTL | Mods::LSFT).bits(), vec![]));
+    }
+
+    board.tap(3);
+    assert_eq!(board.state(), (0, vec![]));
+}
+
+#[test]
+fn slow_second_tap_arms_again() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(0);
+    board.now += ONESHOT_TAPPING_TERM;
+    board.tap(0);
+    board.switch(2, true);
+    assert_eq!(board.state(), (Mods::LSFT.bits(), vec![0x04]));
+
+    // Not locked
+    board.switch(2, false);
+    board.switch(2, true);
+    assert_eq!(board.state(), (0, vec![0x04]));
+}
+
+#[test]
+fn tapped_modifier_times_out() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(0);
+    board.now += ONESHOT_TIMEOUT;
+    board.switch(2, true);
+    assert_eq!(board.state(), (0, vec![0x04]));
+}
+
+#[test]
+fn tapped_layer_applies_to_the_next_key_only() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(1);
+    assert_eq!(board.processor.active_layers(), 0b11);
+    board.switch(2, true);
+    assert_eq!(board.state(), (0, vec![0x1e]));
+    assert_eq!(board.processor.active_layers(), 0b1);
+
+    // Released from the layer it was pressed on
+    board.switch(2, false);
+    assert_eq!(board.state(), (0, vec![]));
+    board.switch(2, true);
+    assert_eq!(board.state(), (0, vec![0x04]));
+}
+
+#[test]
+fn one_shot_modifier_on_a_one_shot_layer() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(1);
+    board.tap(0);
+    board.switch(2, true);
+    assert_eq!(board.state(), (Mods::LSFT.bits(), vec![0x1e]));
+}
+
+#[test]
+fn double_tapped_layer_stays_locked() {
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(LAYERS, &diagnostics);
+
+    board.tap(1);
+    board.tap(1);
+    board.tap(2);
+    board.tap(2);
+    assert_eq!(board.processor.active_layers(), 0b11);
+
+    board.tap(1);
+    assert_eq!(board.processor.active_layers(), 0b1);
+}
+
+#[test]
+fn one_shot_layer_past_the_keymap_is_ignored() {
+    let diagnostics = Diagnostics::new();
+    // Built by hand, `matrix_layers` rejects it
+    let layers: Layers<1, 1, 1> = [[[KeyCode::OneShot(OneShot::Layer(40))]]];
+    let mut processor = KeyProcessor::new(layers, &diagnostics);
+
+    for pressed in [true, false] {
+        let event = KeyEvent {
+            row: 0,
+            col: 0,
+            pressed,
+        };
+        assert_eq!(processor.process(event, Instant::from_secs(10)), None);
+        assert_eq!(processor.active_layers(), 0b1);
+    }
+}
+
+#[test]
+fn translated_key_is_released_as_pressed() {
+    const MAC_KEYMAP: [Keymap<2>; 1] = keymap! { [ OSM(LCTL) HOME ] };
+    const MAC_LAYERS: Layers<2, 1, 1> = matrix_layers(&[KEYS[0], KEYS[1]], &MAC_KEYMAP);
+    let diagnostics = Diagnostics::new();
+    let mut board = Board::new(MAC_LAYERS, &diagnostics);
+    board.processor.set_os(Some(HostOs::MacOs));
+
+    board.tap(0);
+    board.switch(1, true);
+    // Ctrl+Home is Cmd+Up on macOS
+    assert_eq!(board.state(), (Mods::LGUI.bits(), vec![0x52]));
+    board.switch(1, false);
+    assert_eq!(board.state(), (0, vec![]));
+}
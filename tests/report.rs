@@ -0,0 +1,158 @@
+//! Modifier handling of the report builder.
+
+mod common;
+
+use common::report_state;
+use dactyl_rs::{
+    kc,
+    keycodes::Mods,
+    os::HostOs,
+    report::{APPLE_FN, ReportBuilder},
+};
+
+/// Modifier byte and held keycodes of the builder's report
+fn state(builder: &ReportBuilder) -> (u8, Vec<u8>) {
+    report_state(&builder.report())
+}
+
+#[test]
+fn modified_key_adds_its_modifiers_while_held() {
+    let mut builder = ReportBuilder::new();
+
+    assert!(builder.press(kc!(LCTL(LSFT(T)))));
+    assert_eq!(
+        state(&builder),
+        ((Mods::LCTL | Mods::LSFT).bits(), vec![0x17])
+    );
+
+    builder.release(kc!(LCTL(LSFT(T))));
+    assert_eq!(state(&builder), (0, vec![]));
+}
+
+#[test]
+fn held_modifier_survives_a_modified_key() {
+    let mut builder = ReportBuilder::new();
+
+    builder.press(kc!(LSFT));
+    builder.press(kc!(LPRN));
+    assert_eq!(state(&builder), (Mods::LSFT.bits(), vec![0x26]));
+
+    builder.release(kc!(LPRN));
+    assert_eq!(state(&builder), (Mods::LSFT.bits(), vec![]));
+}
+
+#[test]
+fn next_key_drops_the_added_modifiers() {
+    let mut builder = ReportBuilder::new();
+
+    builder.press(kc!(LPRN));
+    builder.press(kc!(A));
+    assert_eq!(state(&builder), (0, vec![0x26, 0x04]));
+
+    builder.release(kc!(LPRN));
+    assert_eq!(state(&builder), (0, vec![0x04]));
+}
+
+#[test]
+fn modifier_held_by_two_keys() {
+    let mut builder = ReportBuilder::new();
+
+    builder.press(kc!(LCTL));
+    builder.press(kc!(LCTL(LSFT)));
+    assert_eq!(state(&builder).0, (Mods::LCTL | Mods::LSFT).bits());
+
+    builder.release(kc!(LCTL(LSFT)));
+    assert_eq!(state(&builder).0, Mods::LCTL.bits());
+    builder.release(kc!(LCTL));
+    assert_eq!(state(&builder).0, 0);
+}
+
+#[test]
+fn apple_fn_in_the_reserved_byte() {
+    let mut builder = ReportBuilder::new();
+
+    builder.press(kc!(AP_GLOB));
+    builder.press(kc!(F));
+    let report = builder.report();
+    assert_eq!(report.reserved, APPLE_FN);
+    assert_eq!(state(&builder), (0, vec![0x09]));
+
+    builder.release(kc!(AP_GLOB));
+    assert_eq!(builder.report().reserved, 0);
+}
+
+#[test]
+fn macos_swaps_ctrl_and_cmd() {
+    let mut builder = ReportBuilder::new();
+    builder.set_os(Some(HostOs::MacOs));
+
+    builder.press(kc!(C(C)));
+    assert_eq!(state(&builder), (Mods::LGUI.bits(), vec![0x06]));
+    builder.release(kc!(C(C)));
+
+    builder.press(kc!(LGUI));
+    builder.press(kc!(LSFT));
+    assert_eq!(state(&builder), ((Mods::LCTL | Mods::LSFT).bits(), vec![]));
+    builder.release(kc!(LGUI));
+    assert_eq!(state(&builder), (Mods::LSFT.bits(), vec![]));
+}
+
+#[test]
+fn macos_shortcuts() {
+    let mut builder = ReportBuilder::new();
+    builder.set_os(Some(HostOs::MacOs));
+
+    builder.press(kc!(HOME));
+    assert_eq!(state(&builder), (Mods::LGUI.bits(), vec![0x50]));
+    builder.release(kc!(HOME));
+    assert_eq!(state(&builder), (0, vec![]));
+
+    builder.press(kc!(C(BSPC)));
+    assert_eq!(state(&builder), (Mods::LALT.bits(), vec![0x2a]));
+}
+
+#[test]
+fn other_hosts_get_keys_as_mapped() {
+    for os in [None, Some(HostOs::Linux), Some(HostOs::Windows)] {
+        let mut builder = ReportBuilder::new();
+        builder.set_os(os);
+        builder.press(kc!(C(C)));
+        builder.press(kc!(HOME));
+        assert_eq!(state(&builder), (0, vec![0x06, 0x4a]));
+    }
+}
+
+#[test]
+fn switching_os_drops_held_keys() {
+    let mut builder = ReportBuilder::new();
+    builder.press(kc!(LCTL));
+    builder.press(kc!(A));
+
+    builder.set_os(Some(HostOs::MacOs));
+    assert_eq!(state(&builder), (0, vec![]));
+
+    // Releasing them afterwards is harmless
+    builder.release(kc!(LCTL));
+    builder.release(kc!(A));
+    assert_eq!(state(&builder), (0, vec![]));
+}
+
+#[test]
+fn typing_keeps_held_keys_without_their_modifiers() {
+    let mut builder = ReportBuilder::new();
+    builder.press(kc!(LCTL));
+    builder.press(kc!(A));
+
+    let press = builder.typing(Mods::LSFT, 0x0b);
+    assert_eq!(
+        (press.modifier, press.keycodes),
+        (Mods::LSFT.bits(), [0x04, 0x0b, 0, 0, 0, 0])
+    );
+    let release = builder.typing(Mods::NONE, 0);
+    assert_eq!(
+        (release.modifier, release.keycodes),
+        (0, [0x04, 0, 0, 0, 0, 0])
+    );
+    // The held state is left as it was
+    assert_eq!(state(&builder), (Mods::LCTL.bits(), vec![0x04]));
+}
@@ -0,0 +1,253 @@
+//! Runs the keyboard's USB stack against an in-memory bus and checks what the
+//! host sees: the descriptors it enumerates and the reports sent for key
+//! events.
+
+mod common;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use common::{CONFIGURATION_DESCRIPTOR, DEVICE_DESCRIPTOR, Host, MockDriver, Wire};
+use dactyl_rs::{
+    board::nrfmicro::BOARD,
+    diagnostics::Diagnostics,
+    fingerprint::HostFingerprint,
+    identity::DEVICE_RELEASE,
+    keycodes::KeyCode,
+    layout::Layout,
+    matrix::KeyEvent,
+    processor::{Effect, KeyProcessor},
+    report::ReportBuilder,
+    usb::{self, HidError, UsbHandler, UsbKeyboard},
+};
+use embassy_futures::{block_on, select::select};
+use embassy_time::Instant;
+use embassy_usb::class::hid::{HidReaderWriter, HidWriter, State};
+use usbd_hid::descriptor::{KeyboardReport, KeyboardUsage};
+
+type Keyboard<'d> = UsbKeyboard<'d, HidWriter<'d, MockDriver<'d>, { usb::KEYBOARD_REPORT_LEN }>>;
+
+/// Builds the keyboard device on a fresh [`Wire`] the way the firmware does
+/// and runs `test` next to the USB stack
+fn with_keyboard(test: impl AsyncFnOnce(&Host<'_>, &mut Keyboard<'_>, &AtomicBool)) {
+    let wire = Wire::new();
+    let host = Host::new(&wire);
+    let configured = AtomicBool::new(false);
+    let suspended = AtomicBool::new(false);
+    let fingerprint = HostFingerprint::new();
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut device_handler = UsbHandler::new(&configured, &suspended, &fingerprint);
+    let mut state = State::new();
+
+    let config = usb::device_config(&BOARD.usb, BOARD.usb.product, "0123456789ABCDEF");
+    let mut builder = embassy_usb::Builder::new(
+        wire.driver(),
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+    builder.handler(&mut device_handler);
+
+    let hid = HidReaderWriter::<_, 1, { usb::KEYBOARD_REPORT_LEN }>::new(
+        &mut builder,
+        &mut state,
+        usb::keyboard_hid_config(BOARD.usb_poll_ms, None),
+    );
+    let (_reader, writer) = hid.split();
+    let mut keyboard = UsbKeyboard::new(writer, &configured);
+    let mut device = builder.build();
+
+    block_on(select(
+        device.run(),
+        test(&host, &mut keyboard, &configured),
+    ));
+}
+
+fn report_bytes(modifier: u8, keycodes: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![modifier, 0, 0, 0, 0, 0, 0, 0];
+    bytes[2..2 + keycodes.len()].copy_from_slice(keycodes);
+    bytes
+}
+
+#[test]
+fn enumerates_with_board_identity() {
+    with_keyboard(async |host, _keyboard, configured| {
+        host.enumerate().await;
+        assert!(configured.load(Ordering::Relaxed));
+
+        let device = host.get_descriptor(DEVICE_DESCRIPTOR, 18).await;
+        assert_eq!(device[0], 18);
+        assert_eq!(device[1], DEVICE_DESCRIPTOR);
+        assert_eq!(u16::from_le_bytes([device[8], device[9]]), BOARD.usb.vid);
+        assert_eq!(u16::from_le_bytes([device[10], device[11]]), BOARD.usb.pid);
+        assert_eq!(u16::from_le_bytes([device[12], device[13]]), DEVICE_RELEASE);
+
+        let config = host.get_descriptor(CONFIGURATION_DESCRIPTOR, 255).await;
+        assert_eq!(
+            usize::from(u16::from_le_bytes([config[2], config[3]])),
+            config.len()
+        );
+        // bMaxPower is in units of 2mA
+        assert_eq!(u16::from(config[8]) * 2, BOARD.usb.max_power);
+
+        let mut descriptors = Vec::new();
+        let mut rest = &config[..];
+        while let [len, ..] = rest
+            && *len > 0
+        {
+            let (descriptor, tail) = rest.split_at(usize::from(*len));
+            descriptors.push(descriptor);
+            rest = tail;
+        }
+        // Interface descriptor with the HID class
+        assert!(
+            descriptors.iter().any(|d| d[1] == 0x04 && d[5] == 0x03),
+            "no HID interface in {config:02x?}"
+        );
+        // HID endpoint polled at the board's interval
+        assert!(
+            descriptors
+                .iter()
+                .any(|d| d[1] == 0x05 && d[2] & 0x80 != 0 && d[6] == BOARD.usb_poll_ms),
+            "no interrupt IN endpoint in {config:02x?}"
+        );
+    });
+}
+
+#[test]
+fn refuses_reports_before_configuration() {
+    with_keyboard(async |host, keyboard, _configured| {
+        assert_eq!(
+            keyboard.send_report(&KeyboardReport::default()).await,
+            Err(HidError::NotConfigured)
+        );
+        assert!(host.take_written().is_empty());
+    });
+}
+
+#[test]
+fn reports_held_keys_and_modifiers() {
+    let layout: Layout<2, 1> = [[
+        KeyCode::Base(KeyboardUsage::KeyboardLeftShift),
+        KeyCode::Base(KeyboardUsage::KeyboardAa),
+    ]];
+    let diagnostics = Diagnostics::new();
+    let mut processor = KeyProcessor::new([layout], &diagnostics);
+
+    with_keyboard(async |host, keyboard, _configured| {
+        host.enumerate().await;
+
+        let events = [(0, true), (1, true), (0, false), (1, false)];
+        for (col, pressed) in events {
+            let event = KeyEvent {
+                row: 0,
+                col,
+                pressed,
+            };
+            assert_eq!(
+                processor.process(event, Instant::now()),
+                Some(Effect::Report)
+            );
+            keyboard.send_report(&processor.report()).await.unwrap();
+        }
+
+        assert_eq!(
+            host.take_written(),
+            [
+                report_bytes(0x02, &[]),
+                report_bytes(0x02, &[0x04]),
+                report_bytes(0x00, &[0x04]),
+                report_bytes(0x00, &[]),
+            ]
+        );
+    });
+}
+
+#[test]
+fn counts_keys_beyond_rollover() {
+    let layout: Layout<7, 1> = [[
+        KeyCode::Base(KeyboardUsage::KeyboardAa),
+        KeyCode::Base(KeyboardUsage::KeyboardBb),
+        KeyCode::Base(KeyboardUsage::KeyboardCc),
+        KeyCode::Base(KeyboardUsage::KeyboardDd),
+        KeyCode::Base(KeyboardUsage::KeyboardEe),
+        KeyCode::Base(KeyboardUsage::KeyboardFf),
+        KeyCode::Base(KeyboardUsage::KeyboardGg),
+    ]];
+    let diagnostics = Diagnostics::new();
+    let mut processor = KeyProcessor::new([layout], &diagnostics);
+
+    with_keyboard(async |host, keyboard, _configured| {
+        host.enumerate().await;
+
+        for col in 0..7 {
+            let event = KeyEvent {
+                row: 0,
+                col,
+                pressed: true,
+            };
+            processor.process(event, Instant::now());
+            keyboard.send_report(&processor.report()).await.unwrap();
+        }
+
+        let written = host.take_written();
+        assert_eq!(
+            written.last(),
+            Some(&report_bytes(0x00, &[0x04, 0x05, 0x06, 0x07, 0x08, 0x09]))
+        );
+    });
+
+    assert_eq!(diagnostics.counters().rollover, 1);
+}
+
+#[test]
+fn types_text_with_shift() {
+    with_keyboard(async |host, keyboard, _configured| {
+        host.enumerate().await;
+
+        keyboard
+            .type_text("Hi", &ReportBuilder::new(), false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            host.take_written(),
+            [
+                report_bytes(0x02, &[0x0b]),
+                report_bytes(0x00, &[]),
+                report_bytes(0x00, &[0x0c]),
+                report_bytes(0x00, &[]),
+                report_bytes(0x00, &[]),
+            ]
+        );
+    });
+}
+
+#[test]
+fn types_text_on_top_of_held_keys() {
+    with_keyboard(async |host, keyboard, _configured| {
+        host.enumerate().await;
+
+        let mut held = ReportBuilder::new();
+        held.press(KeyCode::Base(KeyboardUsage::KeyboardLeftShift));
+        held.press(KeyCode::Base(KeyboardUsage::KeyboardAa));
+        // Caps Lock flips the case back to the one written
+        keyboard.type_text("Hi", &held, true).await.unwrap();
+
+        assert_eq!(
+            host.take_written(),
+            [
+                report_bytes(0x00, &[0x04, 0x0b]),
+                report_bytes(0x00, &[0x04]),
+                report_bytes(0x02, &[0x04, 0x0c]),
+                report_bytes(0x00, &[0x04]),
+                report_bytes(0x02, &[0x04]),
+            ]
+        );
+    });
+}